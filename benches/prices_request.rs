@@ -0,0 +1,76 @@
+//! Serialization of the upstream prices request for a large basket,
+//! through an intermediate `json!` value as the proxy used to, and
+//! straight from the request struct as `fetch_upstream_payload` does.
+//!
+//! The crate is a binary, so the request struct is mirrored here rather
+//! than imported.
+
+use std::collections::HashMap;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use serde::Serialize;
+use serde_json::json;
+
+const BASKET_SIZES: [usize; 2] = [20, 200];
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct UpstreamPricesRequest<'a> {
+    product: &'static str,
+    currency: &'a str,
+    products: &'a [(String, usize)],
+    country: &'static str,
+    dealer: Option<&'static str>,
+}
+
+fn basket(size: usize) -> HashMap<String, usize> {
+    (0..size)
+        .map(|i| (format!("DRIVER-{i:04}"), i % 10 + 1))
+        .collect()
+}
+
+fn with_json_value(basket: HashMap<String, usize>) -> String {
+    let products = basket.into_iter().collect::<Vec<_>>();
+    json!({
+        "Product": "PrintFactory",
+        "Currency": "EUR",
+        "Products": products,
+        "Country": "",
+        "Dealer": null,
+    })
+    .to_string()
+}
+
+fn with_struct(basket: HashMap<String, usize>) -> Vec<u8> {
+    let products: Vec<_> = basket.into_iter().collect();
+    serde_json::to_vec(&UpstreamPricesRequest {
+        product: "PrintFactory",
+        currency: "EUR",
+        products: &products,
+        country: "",
+        dealer: None,
+    })
+    .unwrap()
+}
+
+fn prices_request(c: &mut Criterion) {
+    let mut group = c.benchmark_group("prices_request");
+
+    for size in BASKET_SIZES {
+        let basket = basket(size);
+
+        group.bench_with_input(
+            BenchmarkId::new("json_value", size),
+            &basket,
+            |b, basket| b.iter(|| with_json_value(black_box(basket.clone()))),
+        );
+        group.bench_with_input(BenchmarkId::new("struct", size), &basket, |b, basket| {
+            b.iter(|| with_struct(black_box(basket.clone())))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, prices_request);
+criterion_main!(benches);
@@ -0,0 +1,559 @@
+use std::{
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use axum::http::{
+    header::{AGE, CACHE_CONTROL},
+    HeaderName,
+};
+use lru::LruCache;
+use rand::Rng;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::time;
+use tracing::debug;
+
+use crate::{
+    config::Config,
+    prices::{self, Prices},
+    state::AppState,
+};
+
+static X_CACHE: HeaderName = HeaderName::from_static("x-cache");
+
+/// Spreads the given TTL by ±`fraction` so that entries populated at
+/// the same time do not all expire at once.
+pub fn jitter(ttl: Duration, fraction: f64) -> Duration {
+    let factor = 1.0 + rand::thread_rng().gen_range(-fraction..=fraction);
+    ttl.mul_f64(factor)
+}
+
+/// Computes the canonical key of a basket, hashing everything upstream
+/// prices depend on: the currency, the country, the dealer, then every
+/// product code and quantity. Products are sorted by code so that the
+/// order in which clients send them does not matter.
+///
+/// The same basket priced in two currencies thus gets two entries.
+/// Markups and the decimal pricing are left out, since they apply to
+/// the whole process, as are output options which apply to cached
+/// prices.
+pub fn basket_key(products: &[(String, usize)], currency: &str) -> String {
+    let mut products = products.to_vec();
+    products.sort();
+
+    let mut hasher = Sha256::new();
+    hasher.update(currency.as_bytes());
+    hasher.update(b"\0");
+    hasher.update(prices::COUNTRY.as_bytes());
+    hasher.update(b"\0");
+    hasher.update(prices::DEALER.as_bytes());
+    for (code, qty) in products {
+        hasher.update(b"\0");
+        hasher.update(code.as_bytes());
+        hasher.update(b"\0");
+        hasher.update(qty.to_string().as_bytes());
+    }
+
+    format!("{:x}", hasher.finalize())
+}
+
+#[derive(Clone, Copy)]
+pub enum CacheStatus {
+    /// Fetched from upstream for this request.
+    Miss,
+    Hit,
+    /// Expired, served while being refreshed in the background.
+    Stale,
+}
+
+impl CacheStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Miss => "MISS",
+            Self::Hit => "HIT",
+            Self::Stale => "STALE",
+        }
+    }
+}
+
+/// Freshness of a response, advertised to downstream caches.
+pub struct Freshness {
+    pub status: CacheStatus,
+    pub age: Duration,
+    pub max_age: Duration,
+}
+
+impl Freshness {
+    pub fn miss(ttl: Duration) -> Self {
+        Self {
+            status: CacheStatus::Miss,
+            age: Duration::ZERO,
+            max_age: ttl,
+        }
+    }
+
+    /// When the prices were fetched from upstream.
+    pub fn fetched_at(&self) -> SystemTime {
+        SystemTime::now() - self.age
+    }
+
+    pub fn headers(&self) -> [(HeaderName, String); 3] {
+        let cache_control = match self.status {
+            CacheStatus::Miss | CacheStatus::Hit => {
+                format!("public, max-age={}", self.max_age.as_secs())
+            }
+            CacheStatus::Stale => {
+                format!(
+                    "public, max-age={}, must-revalidate",
+                    self.max_age.as_secs()
+                )
+            }
+        };
+
+        [
+            (CACHE_CONTROL, cache_control),
+            (AGE, self.age.as_secs().to_string()),
+            (X_CACHE.clone(), self.status.as_str().to_owned()),
+        ]
+    }
+}
+
+pub enum Lookup {
+    Hit(Prices, Freshness),
+    /// The caller is in charge of refreshing the entry when `refresh`
+    /// is true, so that only one refresh runs at a time.
+    Stale {
+        prices: Prices,
+        freshness: Freshness,
+        refresh: bool,
+    },
+    Miss,
+}
+
+struct Entry {
+    prices: Prices,
+    fetched_at: Instant,
+    expires_at: Instant,
+    refreshing: bool,
+    /// Approximate memory footprint, see [`PricesCache::insert`].
+    size: usize,
+}
+
+struct Entries {
+    lru: LruCache<String, Entry>,
+    bytes: usize,
+    /// Bumped on every clear, see [`PricesCache::insert`].
+    generation: u64,
+}
+
+impl Entries {
+    fn pop(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.lru.pop(key)?;
+        self.bytes -= entry.size;
+        Some(entry)
+    }
+}
+
+#[derive(Serialize)]
+pub struct PricesCacheStats {
+    entries: usize,
+    bytes: usize,
+    max_entries: usize,
+    max_bytes: usize,
+}
+
+/// Prices cache evicting least recently used baskets once it holds
+/// more than `max_entries` entries or roughly more than `max_bytes`.
+pub struct PricesCache {
+    entries: Mutex<Entries>,
+    max_entries: usize,
+    max_bytes: usize,
+}
+
+impl PricesCache {
+    pub fn new(config: &Config) -> Self {
+        let entries = Entries {
+            lru: LruCache::unbounded(),
+            bytes: 0,
+            generation: 0,
+        };
+
+        Self {
+            entries: Mutex::new(entries),
+            max_entries: config.prices_cache_max_entries,
+            max_bytes: config.prices_cache_max_bytes,
+        }
+    }
+
+    /// Looks the given basket up. Expired entries are served stale
+    /// for `PRICES_CACHE_STALE`, read from the given configuration so
+    /// that it can be reloaded.
+    pub fn get(&self, key: &str, config: &Config) -> Lookup {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+
+        let Some(entry) = entries.lru.get_mut(key) else {
+            return Lookup::Miss;
+        };
+
+        let age = now - entry.fetched_at;
+
+        if now < entry.expires_at {
+            let freshness = Freshness {
+                status: CacheStatus::Hit,
+                age,
+                max_age: entry.expires_at - now,
+            };
+            return Lookup::Hit(entry.prices.clone(), freshness);
+        }
+
+        if now < entry.expires_at + config.prices_cache_stale {
+            let refresh = !entry.refreshing;
+            entry.refreshing = true;
+
+            return Lookup::Stale {
+                prices: entry.prices.clone(),
+                freshness: Freshness {
+                    status: CacheStatus::Stale,
+                    age,
+                    max_age: config.prices_cache_stale_max_age,
+                },
+                refresh,
+            };
+        }
+
+        entries.pop(key);
+        Lookup::Miss
+    }
+
+    /// Allows another refresh of a stale entry after a failed one.
+    pub fn release(&self, key: &str) {
+        if let Some(entry) = self.entries.lock().unwrap().lru.peek_mut(key) {
+            entry.refreshing = false;
+        }
+    }
+
+    /// Generation of the cache, to take before fetching prices to
+    /// insert, see [`PricesCache::insert`].
+    pub fn generation(&self) -> u64 {
+        self.entries.lock().unwrap().generation
+    }
+
+    /// Caches the given prices, returning the ones they replace. Prices
+    /// fetched before the cache was cleared, as of the given
+    /// generation, are dropped: they may have been computed with the
+    /// configuration the cache was cleared for.
+    pub fn insert(
+        &self,
+        key: String,
+        prices: Prices,
+        ttl: Duration,
+        generation: u64,
+    ) -> Option<Prices> {
+        // The serialized size is a good enough approximation of the
+        // memory held by an entry.
+        let size = key.len() + serde_json::to_vec(&prices).map_or(0, |json| json.len());
+        let fetched_at = Instant::now();
+        let expires_at = fetched_at + ttl;
+        let entry = Entry {
+            prices,
+            fetched_at,
+            expires_at,
+            refreshing: false,
+            size,
+        };
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.generation != generation {
+            debug!("dropping prices of basket {key} fetched before the cache was cleared");
+            return None;
+        }
+
+        let previous = entries.pop(&key).map(|entry| entry.prices);
+        entries.bytes += size;
+        entries.lru.push(key, entry);
+
+        while entries.lru.len() > self.max_entries || entries.bytes > self.max_bytes {
+            match entries.lru.pop_lru() {
+                Some((key, entry)) => {
+                    debug!("evicting prices of basket {key} from cache");
+                    entries.bytes -= entry.size;
+                }
+                None => break,
+            }
+        }
+
+        previous
+    }
+
+    /// Removes entries that cannot be served anymore, even stale, and
+    /// returns how many were removed. Expired entries are collected in
+    /// one pass, then removed in small batches so that request-path
+    /// lookups never wait long for the lock.
+    pub fn sweep(&self, config: &Config) -> usize {
+        const BATCH: usize = 256;
+
+        let now = Instant::now();
+        let is_expired = |entry: &Entry| entry.expires_at + config.prices_cache_stale <= now;
+        let expired: Vec<String> = self
+            .entries
+            .lock()
+            .unwrap()
+            .lru
+            .iter()
+            .filter(|(_, entry)| is_expired(entry))
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        let mut swept = 0;
+
+        for keys in expired.chunks(BATCH) {
+            let mut entries = self.entries.lock().unwrap();
+            for key in keys {
+                // The entry may have been refreshed in the meantime.
+                let expired = entries.lru.peek(key).is_some_and(is_expired);
+                if expired {
+                    entries.pop(key);
+                    swept += 1;
+                }
+            }
+        }
+
+        swept
+    }
+
+    /// Removes every entry, so that prices are fetched again. Fetches
+    /// in progress are not cached once done.
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.lru.clear();
+        entries.bytes = 0;
+        entries.generation += 1;
+    }
+
+    pub fn stats(&self) -> PricesCacheStats {
+        let entries = self.entries.lock().unwrap();
+
+        PricesCacheStats {
+            entries: entries.lru.len(),
+            bytes: entries.bytes,
+            max_entries: self.max_entries,
+            max_bytes: self.max_bytes,
+        }
+    }
+}
+
+pub fn spawn_prices_sweeper(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        loop {
+            time::sleep(state.config().prices_cache_sweep_interval).await;
+            sweep_prices(&state);
+        }
+    });
+}
+
+/// Sweeps the prices cache once and records how many entries went.
+fn sweep_prices(state: &AppState) {
+    let swept = state.prices.sweep(&state.config());
+    debug!("swept {swept} expired prices from cache");
+
+    let metrics = &state.metrics;
+    metrics
+        .prices_cache_swept_total
+        .fetch_add(swept as u64, Ordering::Relaxed);
+    metrics
+        .prices_cache_last_sweep
+        .store(swept as u64, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::{body::Bytes, http::StatusCode, routing, Json};
+    use serde_json::{json, Value};
+
+    use crate::{
+        prices::Prices,
+        testing::{self, Upstream},
+    };
+
+    use super::{basket_key, sweep_prices, CacheStatus, Freshness, Lookup, PricesCache};
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    fn is_cached(cache: &PricesCache, key: &str) -> bool {
+        !matches!(cache.get(key, &testing::config([])), Lookup::Miss)
+    }
+
+    #[test]
+    fn evicts_least_recently_used_entries() {
+        let cache = PricesCache::new(&testing::config([("PRICES_CACHE_MAX_ENTRIES", "2")]));
+        cache.insert("a".into(), Prices::default(), TTL, 0);
+        cache.insert("b".into(), Prices::default(), TTL, 0);
+        assert!(is_cached(&cache, "a"));
+
+        cache.insert("c".into(), Prices::default(), TTL, 0);
+        assert!(is_cached(&cache, "a"));
+        assert!(!is_cached(&cache, "b"));
+        assert!(is_cached(&cache, "c"));
+    }
+
+    #[test]
+    fn evicts_entries_over_the_byte_budget() {
+        let size = 1 + serde_json::to_vec(&Prices::default()).unwrap().len();
+        let max_bytes = (size * 5 / 2).to_string();
+        let cache = PricesCache::new(&testing::config([("PRICES_CACHE_MAX_BYTES", &*max_bytes)]));
+
+        cache.insert("a".into(), Prices::default(), TTL, 0);
+        cache.insert("b".into(), Prices::default(), TTL, 0);
+        assert_eq!(cache.entries.lock().unwrap().bytes, size * 2);
+
+        cache.insert("c".into(), Prices::default(), TTL, 0);
+        assert!(!is_cached(&cache, "a"));
+        assert!(is_cached(&cache, "b"));
+        assert!(is_cached(&cache, "c"));
+        assert_eq!(cache.entries.lock().unwrap().bytes, size * 2);
+    }
+
+    #[test]
+    fn drops_prices_fetched_before_a_clear() {
+        let cache = PricesCache::new(&testing::config([]));
+        let generation = cache.generation();
+        cache.insert("a".into(), Prices::default(), TTL, generation);
+
+        cache.clear();
+        assert!(!is_cached(&cache, "a"));
+        let previous = cache.insert("b".into(), Prices::default(), TTL, generation);
+        assert!(previous.is_none());
+        assert!(!is_cached(&cache, "b"));
+
+        cache.insert("b".into(), Prices::default(), TTL, cache.generation());
+        assert!(is_cached(&cache, "b"));
+    }
+
+    #[tokio::test]
+    async fn sweeps_expired_entries() {
+        let state = testing::state([("PRICES_CACHE_STALE", "0")]);
+        let cache = &state.prices;
+        cache.insert("fresh".into(), Prices::default(), TTL, 0);
+        cache.insert("expired-a".into(), Prices::default(), Duration::ZERO, 0);
+        cache.insert("expired-b".into(), Prices::default(), Duration::ZERO, 0);
+
+        assert_eq!(cache.sweep(&state.config()), 2);
+        assert_eq!(cache.stats().entries, 1);
+        assert!(is_cached(cache, "fresh"));
+        assert_eq!(cache.sweep(&state.config()), 0);
+
+        cache.insert("expired-c".into(), Prices::default(), Duration::ZERO, 0);
+        sweep_prices(&state);
+        sweep_prices(&state);
+
+        let res = testing::send(&state, testing::get("/metrics")).await;
+        let metrics = String::from_utf8_lossy(&res.bytes);
+        assert!(metrics.contains("\nprices_cache_swept_total 1\n"));
+        assert!(metrics.contains("\nprices_cache_last_sweep 0\n"));
+    }
+
+    fn headers(freshness: &Freshness) -> Vec<(String, String)> {
+        freshness
+            .headers()
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect()
+    }
+
+    #[test]
+    fn advertises_freshness_per_cache_status() {
+        let miss = Freshness::miss(Duration::from_secs(3600));
+        let hit = Freshness {
+            status: CacheStatus::Hit,
+            age: Duration::from_secs(600),
+            max_age: Duration::from_secs(3000),
+        };
+        let stale = Freshness {
+            status: CacheStatus::Stale,
+            age: Duration::from_secs(3700),
+            max_age: Duration::from_secs(10),
+        };
+
+        let header = |name: &str, value: &str| (name.to_owned(), value.to_owned());
+        assert_eq!(
+            headers(&miss),
+            [
+                header("cache-control", "public, max-age=3600"),
+                header("age", "0"),
+                header("x-cache", "MISS"),
+            ]
+        );
+        assert_eq!(
+            headers(&hit),
+            [
+                header("cache-control", "public, max-age=3000"),
+                header("age", "600"),
+                header("x-cache", "HIT"),
+            ]
+        );
+        assert_eq!(
+            headers(&stale),
+            [
+                header("cache-control", "public, max-age=10, must-revalidate"),
+                header("age", "3700"),
+                header("x-cache", "STALE"),
+            ]
+        );
+    }
+
+    #[test]
+    fn keys_baskets_per_currency() {
+        let basket = [
+            ("EPSON-SCP9500".to_owned(), 1),
+            ("HP-LATEX-800W".to_owned(), 2),
+        ];
+        let reversed = [basket[1].clone(), basket[0].clone()];
+
+        assert_eq!(basket_key(&basket, "EUR"), basket_key(&reversed, "EUR"));
+        assert_ne!(basket_key(&basket, "EUR"), basket_key(&basket, "USD"));
+        assert_ne!(basket_key(&basket, "EUR"), basket_key(&basket[..1], "EUR"));
+    }
+
+    #[tokio::test]
+    async fn caches_each_currency_separately() {
+        // Prices in USD are a dollar higher.
+        let prices = routing::post(|body: Bytes| async move {
+            let body: Value = serde_json::from_slice(&body).unwrap();
+            let price = if body["Currency"] == "USD" {
+                30.9
+            } else {
+                29.9
+            };
+            Json(json!({ "Type": "Subscription", "Results": [["Connect", 30, 1, price]] }))
+        });
+        let upstream = Upstream::start(testing::drivers().route("/_prices.asp", prices)).await;
+        let state = upstream.state([]);
+
+        let mut responses = Vec::new();
+        for currency in ["EUR", "USD", "EUR", "USD"] {
+            let uri = format!("/prices/EPSON-SCP9500?currency={currency}");
+            let res = testing::send(&state, testing::get(&uri)).await;
+            assert_eq!(res.status, StatusCode::OK);
+            let cache = res.header("x-cache").unwrap().to_owned();
+            responses.push((res.body["monthly"]["connect"].clone(), cache));
+        }
+
+        let response = |cents: i64, cache: &str| (json!(cents), cache.to_owned());
+        assert_eq!(
+            responses,
+            [
+                response(2990, "MISS"),
+                response(3090, "MISS"),
+                response(2990, "HIT"),
+                response(3090, "HIT"),
+            ]
+        );
+        assert_eq!(upstream.requests_to("/_prices.asp").len(), 2);
+        let stats = serde_json::to_value(state.prices.stats()).unwrap();
+        assert_eq!(stats["entries"], 2);
+    }
+}
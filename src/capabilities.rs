@@ -0,0 +1,107 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    extract::State,
+    http::header::CACHE_CONTROL,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+
+use crate::{config::PricesRequestFormat, state::AppState};
+
+/// Version of the HTTP API, bumped on breaking changes.
+static API_VERSION: &str = "1";
+
+#[derive(Serialize)]
+struct Capabilities {
+    api_version: &'static str,
+    formats: [&'static str; 2],
+    locales: [&'static str; 2],
+    drivers_shapes: [&'static str; 2],
+    include: Vec<&'static str>,
+    graphql: bool,
+    admin: bool,
+    forward_trace_headers: bool,
+    plan_aliases: HashMap<String, String>,
+    prices_request_format: &'static str,
+    request_timeout_secs: u64,
+    prices_cache_ttl_secs: u64,
+    prices_cache_stale_secs: u64,
+    max_quantity: usize,
+    default_quantity: usize,
+    envelope: bool,
+}
+
+/// Describes the features enabled on this deployment, so that clients
+/// can adapt to it. Secrets are never part of it.
+pub async fn capabilities(State(state): State<Arc<AppState>>) -> Response {
+    let config = &state.config();
+
+    let capabilities = Capabilities {
+        api_version: API_VERSION,
+        formats: ["cents", "localized"],
+        locales: ["en", "fr"],
+        drivers_shapes: ["array", "map"],
+        include: vec!["fetched_at", "type"],
+        graphql: true,
+        admin: config.admin_token.is_some(),
+        forward_trace_headers: config.forward_trace_headers,
+        plan_aliases: config.plan_aliases.clone(),
+        prices_request_format: match config.prices_request_format {
+            PricesRequestFormat::Json => "json",
+            PricesRequestFormat::Form => "form",
+        },
+        // Only applied at startup, see `Config::restart_required`.
+        request_timeout_secs: state.startup_config().request_timeout.as_secs(),
+        prices_cache_ttl_secs: config.prices_cache_ttl.as_secs(),
+        prices_cache_stale_secs: config.prices_cache_stale.as_secs(),
+        max_quantity: config.max_quantity,
+        default_quantity: config.default_quantity,
+        envelope: config.envelope,
+    };
+
+    ([(CACHE_CONTROL, "no-store")], Json(capabilities)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    use crate::testing;
+
+    #[tokio::test]
+    async fn describes_the_deployment() {
+        let state = testing::state([]);
+
+        let res = testing::send(&state, testing::get("/capabilities")).await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.header("cache-control"), Some("no-store"));
+        assert_eq!(res.body["api_version"], "1");
+        assert_eq!(res.body["formats"], json!(["cents", "localized"]));
+        assert_eq!(res.body["admin"], false);
+        assert_eq!(res.body["plan_aliases"], json!({}));
+        assert_eq!(res.body["prices_request_format"], "json");
+        assert_eq!(res.body["request_timeout_secs"], 20);
+        assert_eq!(res.body["prices_cache_ttl_secs"], 3600);
+        assert_eq!(res.body["max_quantity"], 10_000);
+
+        let vars = [
+            ("ADMIN_TOKEN", "t0ken"),
+            ("UPSTREAM_API_KEY", "s3cret"),
+            ("PLAN_ALIASES", r#"{"production":"pro"}"#),
+            ("PRICES_REQUEST_FORMAT", "form"),
+        ];
+        let state = testing::state(vars);
+
+        let res = testing::send(&state, testing::get("/capabilities")).await;
+        assert_eq!(res.body["admin"], true);
+        assert_eq!(res.body["plan_aliases"], json!({ "production": "pro" }));
+        assert_eq!(res.body["prices_request_format"], "form");
+
+        let body = String::from_utf8_lossy(&res.bytes);
+        assert!(!body.contains("t0ken"));
+        assert!(!body.contains("s3cret"));
+    }
+}
@@ -0,0 +1,67 @@
+use std::sync::Arc;
+
+use axum::{extract::State, Json};
+use serde_json::{json, Value};
+
+use crate::{
+    drivers,
+    error::Error,
+    output::Output,
+    prices::{self, Products, DEFAULT_CURRENCY},
+    state::AppState,
+    upstream::Context,
+};
+
+/// Returns the drivers along with the prices of the given basket,
+/// fetched concurrently. Both fetches share the upstream concurrency
+/// limit without risking a deadlock, see [`crate::upstream::send`].
+pub async fn catalog(
+    State(state): State<Arc<AppState>>,
+    ctx: Context,
+    output: Output,
+    Json(products): Json<Products>,
+) -> Result<Json<Value>, Error> {
+    let products = products.0.into_iter().collect();
+
+    let (drivers, prices) = tokio::join!(
+        drivers::cached_drivers(&state),
+        prices::fetch_prices(&state, &ctx, products, DEFAULT_CURRENCY),
+    );
+    let drivers = drivers?;
+    let (prices, freshness) = prices?;
+
+    let mut body = json!({
+        "drivers": &drivers.drivers,
+        "prices": prices.to_body(&freshness, &state.config(), &output)?,
+    });
+    output.apply_case(&mut body);
+
+    Ok(Json(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::http::StatusCode;
+    use serde_json::json;
+    use tokio::time;
+
+    use crate::testing::{self, Upstream};
+
+    #[tokio::test]
+    async fn completes_with_a_single_upstream_permit() {
+        let upstream = Upstream::start(testing::fixtures()).await;
+        let state = upstream.state([("UPSTREAM_CONCURRENCY", "1")]);
+
+        let req = testing::post("/catalog", json!({ "EPSON-SCP9500": 1 }));
+        let res = time::timeout(Duration::from_secs(5), testing::send(&state, req))
+            .await
+            .expect("should not deadlock");
+
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.body["drivers"].as_array().map(Vec::len), Some(4));
+        assert_eq!(res.body["prices"]["monthly"]["connect"], 2990);
+        assert_eq!(state.upstream_permits.available_permits(), 1);
+    }
+}
@@ -0,0 +1,86 @@
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use serde::Serialize;
+use tokio::net;
+
+/// Connection diagnostics gathered around upstream calls. The pool
+/// itself is not observable through reqwest, but a DNS resolution
+/// happens for every new connection, so that resolutions also count
+/// opened connections.
+#[derive(Default)]
+pub struct ConnectionStats {
+    calls: AtomicU64,
+    resolutions: AtomicU64,
+    last_resolution_micros: AtomicU64,
+    /// Whether no resolution happened during the last call, meaning it
+    /// reused a pooled connection. Concurrent calls make it approximate.
+    last_call_reused: AtomicBool,
+}
+
+#[derive(Serialize)]
+pub struct ConnectionStatsReport {
+    calls: u64,
+    /// Roughly the number of connections opened.
+    resolutions: u64,
+    last_resolution_ms: f64,
+    last_call_reused: bool,
+}
+
+impl ConnectionStats {
+    /// Returns the resolutions count, to be given back to
+    /// [`ConnectionStats::call_ended`] once the call is made.
+    pub fn call_started(&self) -> u64 {
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        self.resolutions.load(Ordering::Relaxed)
+    }
+
+    pub fn call_ended(&self, resolutions_before: u64) {
+        let reused = self.resolutions.load(Ordering::Relaxed) == resolutions_before;
+        self.last_call_reused.store(reused, Ordering::Relaxed);
+    }
+
+    pub fn report(&self) -> ConnectionStatsReport {
+        let micros = self.last_resolution_micros.load(Ordering::Relaxed);
+        ConnectionStatsReport {
+            calls: self.calls.load(Ordering::Relaxed),
+            resolutions: self.resolutions.load(Ordering::Relaxed),
+            last_resolution_ms: micros as f64 / 1000.0,
+            last_call_reused: self.last_call_reused.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Resolves names with the system resolver, timing each resolution.
+pub struct TimedResolver(pub Arc<ConnectionStats>);
+
+impl Resolve for TimedResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let stats = self.0.clone();
+
+        Box::pin(async move {
+            let started_at = Instant::now();
+            let addrs: Vec<SocketAddr> = net::lookup_host((name.as_str(), 0)).await?.collect();
+            let micros = started_at
+                .elapsed()
+                .as_micros()
+                .try_into()
+                .unwrap_or(u64::MAX);
+
+            stats.resolutions.fetch_add(1, Ordering::Relaxed);
+            stats
+                .last_resolution_micros
+                .store(micros, Ordering::Relaxed);
+
+            let addrs: Addrs = Box::new(addrs.into_iter());
+            Ok(addrs)
+        })
+    }
+}
@@ -0,0 +1,913 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    mem,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::anyhow;
+use async_graphql::SimpleObject;
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{
+        header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+        HeaderMap, HeaderName, HeaderValue, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tokio::time;
+use tracing::{debug, warn};
+
+use crate::{
+    cache,
+    config::DuplicateDrivers,
+    error::Error,
+    output::{self, Output},
+    query::{self, Query},
+    state::AppState,
+    upstream::{self, Context},
+};
+
+static MOCK_DRIVERS: &str = include_str!("../fixtures/drivers.json");
+
+/// Driver fields that can be selected with `fields`.
+static FIELDS: [&str; 2] = ["name", "code"];
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Drivers(pub Vec<Driver>);
+
+#[derive(Clone, Serialize, Deserialize, SimpleObject)]
+#[serde(rename_all(deserialize = "PascalCase"))]
+pub struct Driver {
+    pub name: String,
+    pub code: String,
+}
+
+pub struct DriversCache {
+    pub drivers: Drivers,
+    pub etag: String,
+    /// Truncated to the second, since HTTP dates cannot carry more
+    /// precision than that.
+    pub last_modified: SystemTime,
+    /// When the drivers were last fetched from upstream, changed or not.
+    pub fetched_at: SystemTime,
+    /// Validators returned by upstream, sent back on refresh to avoid
+    /// downloading an unchanged list.
+    upstream: UpstreamValidators,
+    /// Codes of the drivers, for fast lookups.
+    codes: HashSet<String>,
+    /// Drivers dropped because of a code already taken, see
+    /// `DRIVERS_DUPLICATES`.
+    pub duplicates: Vec<Driver>,
+}
+
+#[derive(Clone, Default)]
+struct UpstreamValidators {
+    etag: Option<HeaderValue>,
+    last_modified: Option<HeaderValue>,
+}
+
+impl UpstreamValidators {
+    fn from_headers(headers: &HeaderMap) -> Self {
+        Self {
+            etag: headers.get(ETAG).cloned(),
+            last_modified: headers.get(LAST_MODIFIED).cloned(),
+        }
+    }
+
+    fn conditional_headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(etag) = &self.etag {
+            headers.insert(IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(last_modified) = &self.last_modified {
+            headers.insert(IF_MODIFIED_SINCE, last_modified.clone());
+        }
+        headers
+    }
+}
+
+/// Outcome of a drivers fetch.
+enum Fetched {
+    /// Upstream answered 304 to the conditional request.
+    NotModified,
+    Modified {
+        drivers: Drivers,
+        duplicates: Vec<Driver>,
+        etag: String,
+        upstream: UpstreamValidators,
+    },
+}
+
+impl DriversCache {
+    fn new(
+        drivers: Drivers,
+        duplicates: Vec<Driver>,
+        etag: String,
+        upstream: UpstreamValidators,
+    ) -> Self {
+        let fetched_at = SystemTime::now();
+        let secs = fetched_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        let codes = drivers.0.iter().map(|d| d.code.clone()).collect();
+
+        Self {
+            drivers,
+            etag,
+            last_modified: UNIX_EPOCH + Duration::from_secs(secs),
+            fetched_at,
+            upstream,
+            codes,
+            duplicates,
+        }
+    }
+
+    pub fn contains(&self, code: &str) -> bool {
+        self.codes.contains(code)
+    }
+
+    /// ETag of the drivers as rendered with the given variant, so that
+    /// a validator of one variant never revalidates another. The
+    /// default variant, empty, keeps the ETag of the list.
+    fn variant_etag(&self, variant: &str) -> String {
+        if variant.is_empty() {
+            return self.etag.clone();
+        }
+
+        let mut hasher = Sha256::new();
+        hasher.update(self.etag.as_bytes());
+        hasher.update(b"\0");
+        hasher.update(variant.as_bytes());
+        format!("\"{:x}\"", hasher.finalize())
+    }
+
+    fn is_not_modified(&self, headers: &HeaderMap, etag: &str) -> bool {
+        // If-None-Match takes precedence over If-Modified-Since, see
+        // RFC 9110 section 13.1.3.
+        if let Some(etags) = headers.get(IF_NONE_MATCH) {
+            let Ok(etags) = etags.to_str() else {
+                return false;
+            };
+
+            return etags
+                .split(',')
+                .map(str::trim)
+                .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag);
+        }
+
+        let since = headers
+            .get(IF_MODIFIED_SINCE)
+            .and_then(|since| since.to_str().ok())
+            .and_then(|since| httpdate::parse_http_date(since).ok());
+
+        match since {
+            Some(since) => since >= self.last_modified,
+            None => false,
+        }
+    }
+
+    fn validators(&self, etag: &str) -> [(HeaderName, String); 2] {
+        [
+            (ETAG, etag.to_owned()),
+            (LAST_MODIFIED, httpdate::fmt_http_date(self.last_modified)),
+        ]
+    }
+}
+
+async fn fetch_drivers(
+    state: &AppState,
+    validators: &UpstreamValidators,
+) -> Result<Fetched, Error> {
+    let (bytes, upstream) = if state.config().mock_mode {
+        let bytes = Bytes::from_static(MOCK_DRIVERS.as_bytes());
+        (bytes, UpstreamValidators::default())
+    } else {
+        let req = state
+            .client
+            .get(state.config().drivers_url.clone())
+            .headers(validators.conditional_headers());
+        let res = upstream::send(state, &Context::default(), req).await?;
+
+        if res.status() == StatusCode::NOT_MODIFIED {
+            return Ok(Fetched::NotModified);
+        }
+
+        let upstream = UpstreamValidators::from_headers(res.headers());
+        (res.bytes().await?, upstream)
+    };
+
+    let drivers: Drivers = serde_json::from_slice(&bytes.slice(..))?;
+    let (drivers, duplicates) = dedup_drivers(drivers, &state.config().drivers_duplicates)?;
+    let etag = format!("\"{:x}\"", Sha256::digest(serde_json::to_vec(&drivers)?));
+
+    Ok(Fetched::Modified {
+        drivers,
+        duplicates,
+        etag,
+        upstream,
+    })
+}
+
+/// Fetches the drivers from upstream and replaces the cache, keeping
+/// the previous `Last-Modified` when the data did not change.
+pub async fn refresh_drivers(state: &AppState) -> Result<Arc<DriversCache>, Error> {
+    let previous = state.drivers.read().unwrap().clone();
+    let validators = match &previous {
+        Some(cache) => cache.upstream.clone(),
+        None => UpstreamValidators::default(),
+    };
+
+    let fetched = fetch_drivers(state, &validators).await?;
+    let mut cache = state.drivers.write().unwrap();
+
+    let fresh = match (fetched, cache.as_ref()) {
+        (Fetched::NotModified, Some(cache)) => {
+            debug!("drivers not modified upstream, skipping download");
+            DriversCache {
+                last_modified: cache.last_modified,
+                ..DriversCache::new(
+                    cache.drivers.clone(),
+                    cache.duplicates.clone(),
+                    cache.etag.clone(),
+                    cache.upstream.clone(),
+                )
+            }
+        }
+        // Only conditional requests can be answered with a 304, and
+        // those are sent when a cache exists.
+        (Fetched::NotModified, None) => {
+            return Err(Error::new(
+                StatusCode::BAD_GATEWAY,
+                anyhow!("upstream answered 304 to an unconditional request"),
+            ));
+        }
+        (
+            Fetched::Modified {
+                drivers,
+                duplicates,
+                etag,
+                upstream,
+            },
+            Some(cache),
+        ) if cache.etag == etag => {
+            debug!("drivers unchanged since last refresh");
+            DriversCache {
+                last_modified: cache.last_modified,
+                ..DriversCache::new(drivers, duplicates, etag, upstream)
+            }
+        }
+        (
+            Fetched::Modified {
+                drivers,
+                duplicates,
+                etag,
+                upstream,
+            },
+            _,
+        ) => {
+            debug!("drivers changed, new etag {etag}");
+            DriversCache::new(drivers, duplicates, etag, upstream)
+        }
+    };
+
+    let changed = cache.as_ref().map(|cache| &cache.etag) != Some(&fresh.etag);
+    let fresh = Arc::new(fresh);
+    *cache = Some(fresh.clone());
+
+    if changed {
+        state.drivers_changed.notify_waiters();
+    }
+
+    Ok(fresh)
+}
+
+pub fn spawn_drivers_refresh(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        loop {
+            if let Err(err) = refresh_drivers(&state).await {
+                warn!("cannot refresh drivers: {err}");
+            }
+
+            let interval = state.config().drivers_refresh_interval;
+            time::sleep(cache::jitter(interval, state.config().cache_jitter)).await;
+        }
+    });
+}
+
+/// Returns the cached drivers, fetching them first if the background
+/// refresh did not populate the cache yet. Requests arriving while they
+/// are fetched wait for that fetch rather than making their own.
+pub async fn cached_drivers(state: &AppState) -> Result<Arc<DriversCache>, Error> {
+    let cache = state.drivers.read().unwrap().clone();
+    if let Some(cache) = cache {
+        return Ok(cache);
+    }
+
+    let _fetching = state.drivers_fetch.lock().await;
+    let cache = state.drivers.read().unwrap().clone();
+    match cache {
+        Some(cache) => Ok(cache),
+        None => refresh_drivers(state).await,
+    }
+}
+
+/// How an empty driver list is answered.
+#[derive(Default, Deserialize)]
+pub enum Empty {
+    /// With an empty array.
+    #[default]
+    #[serde(rename = "array")]
+    Array,
+    /// With a 404, for clients treating an empty list as an error.
+    #[serde(rename = "404")]
+    NotFound,
+}
+
+/// How the driver list is shaped.
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Shape {
+    /// An array of drivers.
+    #[default]
+    Array,
+    /// An object mapping driver codes to names.
+    Map,
+}
+
+#[derive(Deserialize)]
+pub struct DriversQuery {
+    #[serde(default, deserialize_with = "query::last")]
+    empty: Empty,
+    #[serde(default, deserialize_with = "query::last")]
+    shape: Shape,
+}
+
+/// Maps the driver codes to their names. Codes are unique, duplicates
+/// being dropped on refresh.
+fn drivers_by_code(drivers: &Drivers) -> BTreeMap<&str, &str> {
+    drivers
+        .0
+        .iter()
+        .map(|driver| (driver.code.as_str(), driver.name.as_str()))
+        .collect()
+}
+
+/// Keeps a single driver per code following `DRIVERS_DUPLICATES`.
+/// Returns the kept drivers, in upstream order, and the dropped ones.
+fn dedup_drivers(
+    drivers: Drivers,
+    policy: &DuplicateDrivers,
+) -> Result<(Drivers, Vec<Driver>), Error> {
+    let mut kept: Vec<Driver> = Vec::with_capacity(drivers.0.len());
+    let mut indices = HashMap::new();
+    let mut dropped = Vec::new();
+
+    for driver in drivers.0 {
+        let Some(&i) = indices.get(&driver.code) else {
+            indices.insert(driver.code.clone(), kept.len());
+            kept.push(driver);
+            continue;
+        };
+
+        warn!(
+            "upstream returned several drivers with code {}: {:?} and {:?}",
+            driver.code, kept[i].name, driver.name
+        );
+
+        match policy {
+            DuplicateDrivers::FirstWins => dropped.push(driver),
+            DuplicateDrivers::LastWins => dropped.push(mem::replace(&mut kept[i], driver)),
+            DuplicateDrivers::Error => {
+                return Err(Error::new(
+                    StatusCode::BAD_GATEWAY,
+                    anyhow!(
+                        "upstream returned several drivers with code {}",
+                        driver.code
+                    ),
+                ));
+            }
+        }
+    }
+
+    Ok((Drivers(kept), dropped))
+}
+
+pub async fn list_drivers(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<DriversQuery>,
+    headers: HeaderMap,
+    ctx: Context,
+    output: Output,
+) -> Result<Response, Error> {
+    output.check_fields(&state.config(), &FIELDS)?;
+    let cache = cached_drivers(&state).await?;
+
+    if let (Empty::NotFound, true) = (&query.empty, cache.drivers.0.is_empty()) {
+        return Err(Error::new(
+            StatusCode::NOT_FOUND,
+            anyhow!("no drivers found"),
+        ));
+    }
+
+    let fetched_at = output::format_rfc3339(cache.fetched_at);
+    let etag = cache.variant_etag(&variant(&state, &query, &output, &fetched_at));
+
+    if cache.is_not_modified(&headers, &etag) {
+        return Ok((StatusCode::NOT_MODIFIED, cache.validators(&etag)).into_response());
+    }
+
+    let drivers = match query.shape {
+        Shape::Array => {
+            let mut drivers = serde_json::to_value(&cache.drivers)?;
+            if let Value::Array(drivers) = &mut drivers {
+                drivers.iter_mut().for_each(|d| output.select_fields(d));
+            }
+            drivers
+        }
+        // Fields do not apply to the map, which only holds names.
+        Shape::Map => serde_json::to_value(drivers_by_code(&cache.drivers))?,
+    };
+
+    // The list is wrapped in an object only when extra fields are
+    // requested, to keep the default shape stable.
+    let body = if output.includes("fetched_at") {
+        json!({
+            "drivers": drivers,
+            "fetched_at": &fetched_at,
+        })
+    } else {
+        drivers
+    };
+
+    let meta = json!({
+        "etag": &cache.etag,
+        "fetched_at": &fetched_at,
+        "request_id": ctx.request_id(),
+    });
+    let body = output::envelope(&state.config(), body, meta);
+
+    Ok((cache.validators(&etag), Json(body)).into_response())
+}
+
+/// Describes how the drivers are rendered for the given request, empty
+/// for the default rendering.
+fn variant(state: &AppState, query: &DriversQuery, output: &Output, fetched_at: &str) -> String {
+    let mut variant = Vec::new();
+
+    if let Shape::Map = query.shape {
+        variant.push("shape=map".to_owned());
+    }
+    if let Some(fields) = &output.fields {
+        variant.push(format!("fields={}", fields.join(",")));
+    }
+    if output.includes("fetched_at") {
+        variant.push(format!("fetched_at={fetched_at}"));
+    }
+    if state.config().envelope {
+        variant.push("envelope".to_owned());
+    }
+
+    variant.join(";")
+}
+
+#[derive(Deserialize)]
+pub struct PollQuery {
+    /// ETag of the list known by the client, quoted or not.
+    #[serde(default, deserialize_with = "query::last")]
+    version: Option<String>,
+}
+
+/// Answers with the drivers as soon as their ETag differs from the
+/// given version, or with a 304 if it did not change within
+/// `DRIVERS_POLL_TIMEOUT`.
+pub async fn poll_drivers(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<PollQuery>,
+    ctx: Context,
+) -> Result<Response, Error> {
+    let version = query.version.as_deref().map(|v| v.trim_matches('"'));
+
+    let changed = time::timeout(state.config().drivers_poll_timeout, async {
+        loop {
+            // Registered before checking the cache, so that a change
+            // happening in between is not missed.
+            let notified = state.drivers_changed.notified();
+            let cache = cached_drivers(&state).await?;
+
+            if version != Some(cache.etag.trim_matches('"')) {
+                return Ok::<_, Error>(cache);
+            }
+
+            notified.await;
+        }
+    })
+    .await;
+
+    let cache = match changed {
+        Ok(cache) => cache?,
+        Err(_) => {
+            let cache = cached_drivers(&state).await?;
+            return Ok((StatusCode::NOT_MODIFIED, cache.validators(&cache.etag)).into_response());
+        }
+    };
+
+    let meta = json!({
+        "etag": &cache.etag,
+        "fetched_at": output::format_rfc3339(cache.fetched_at),
+        "request_id": ctx.request_id(),
+    });
+    let body = output::envelope(&state.config(), serde_json::to_value(&cache.drivers)?, meta);
+
+    Ok((cache.validators(&cache.etag), Json(body)).into_response())
+}
+
+#[derive(Serialize)]
+pub struct DriversDiff {
+    added: Vec<Driver>,
+    removed: Vec<String>,
+    /// ETag of the driver list the diff was computed against.
+    version: String,
+}
+
+/// Compares the driver codes known by the client against the cached
+/// driver list.
+pub async fn diff_drivers(
+    State(state): State<Arc<AppState>>,
+    Json(codes): Json<Vec<String>>,
+) -> Result<Json<DriversDiff>, Error> {
+    let cache = cached_drivers(&state).await?;
+    let known: HashSet<&str> = codes.iter().map(String::as_str).collect();
+    let current: HashSet<&str> = cache.drivers.0.iter().map(|d| d.code.as_str()).collect();
+
+    let added = cache
+        .drivers
+        .0
+        .iter()
+        .filter(|driver| !known.contains(driver.code.as_str()))
+        .cloned()
+        .collect();
+
+    let removed = codes
+        .iter()
+        .filter(|code| !current.contains(code.as_str()))
+        .cloned()
+        .collect();
+
+    Ok(Json(DriversDiff {
+        added,
+        removed,
+        version: cache.etag.clone(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
+
+    use axum::{
+        http::{HeaderMap, Request, StatusCode},
+        response::IntoResponse,
+        routing, Json, Router,
+    };
+    use serde_json::json;
+    use tokio::time;
+
+    use crate::{
+        config::DuplicateDrivers,
+        testing::{self, Upstream},
+    };
+
+    use super::{cached_drivers, dedup_drivers, refresh_drivers, Driver, Drivers};
+
+    #[tokio::test]
+    async fn diffs_drivers() {
+        let state = testing::state([("MOCK_MODE", "true")]);
+        let known = json!(["EPSON-SCP9500", "HP-LATEX-800W", "CANON-TM300"]);
+        let res = testing::send(&state, testing::post("/drivers/diff", known)).await;
+
+        assert_eq!(res.status, StatusCode::OK);
+        let added: Vec<_> = res.body["added"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|driver| driver["code"].clone())
+            .collect();
+        assert_eq!(added, [json!("MIMAKI-JV330"), json!("ROLAND-VG3-640")]);
+        assert_eq!(res.body["removed"], json!(["CANON-TM300"]));
+
+        let etag = &state.drivers.read().unwrap().clone().unwrap().etag;
+        assert_eq!(res.body["version"], json!(etag));
+    }
+
+    #[tokio::test]
+    async fn answers_empty_lists() {
+        let routes = Router::new().route("/_driverList.asp", routing::get(|| async { "[]" }));
+        let upstream = Upstream::start(routes).await;
+        let state = upstream.state([]);
+
+        let res = testing::send(&state, testing::get("/drivers")).await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.body, json!([]));
+
+        let res = testing::send(&state, testing::get("/drivers?empty=404")).await;
+        assert_eq!(res.status, StatusCode::NOT_FOUND);
+        assert_eq!(res.body["error"], "no drivers found");
+
+        let state = testing::state([("MOCK_MODE", "true")]);
+        let res = testing::send(&state, testing::get("/drivers?empty=404")).await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.body.as_array().map(Vec::len), Some(4));
+    }
+
+    #[tokio::test]
+    async fn fetches_cold_drivers_once() {
+        let drivers = routing::get(|| async {
+            time::sleep(Duration::from_millis(100)).await;
+            testing::DRIVERS
+        });
+        let upstream = Upstream::start(Router::new().route("/_driverList.asp", drivers)).await;
+        let state = upstream.state([]);
+
+        let (a, b) = tokio::join!(cached_drivers(&state), cached_drivers(&state));
+        assert!(Arc::ptr_eq(&a.unwrap(), &b.unwrap()));
+        assert_eq!(upstream.requests_to("/_driverList.asp").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn validates_each_variant_separately() {
+        let state = testing::state([("MOCK_MODE", "true")]);
+
+        let etag = |uri: &'static str| {
+            let state = state.clone();
+            async move {
+                let res = testing::send(&state, testing::get(uri)).await;
+                assert_eq!(res.status, StatusCode::OK);
+                res.header("etag").unwrap().to_owned()
+            }
+        };
+        let array = etag("/drivers").await;
+        let map = etag("/drivers?shape=map").await;
+        let codes = etag("/drivers?fields=code").await;
+        assert_ne!(array, map);
+        assert_ne!(array, codes);
+        assert_ne!(map, codes);
+        assert_eq!(array, state.drivers.read().unwrap().clone().unwrap().etag);
+
+        let revalidate = |uri: &str, etag: &str| {
+            Request::get(uri)
+                .header("if-none-match", etag)
+                .body(Default::default())
+                .unwrap()
+        };
+        let res = testing::send(&state, revalidate("/drivers?shape=map", &map)).await;
+        assert_eq!(res.status, StatusCode::NOT_MODIFIED);
+        assert_eq!(res.header("etag"), Some(map.as_str()));
+
+        let res = testing::send(&state, revalidate("/drivers?shape=map", &array)).await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.body["EPSON-SCP9500"], "Epson SureColor SC-P9500");
+
+        let res = testing::send(&state, revalidate("/drivers", &map)).await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert!(res.body.is_array());
+    }
+
+    #[tokio::test]
+    async fn revalidates_with_if_modified_since() {
+        let state = testing::state([("MOCK_MODE", "true")]);
+        let res = testing::send(&state, testing::get("/drivers")).await;
+        let last_modified = res.header("last-modified").unwrap().to_owned();
+        let last_modified = httpdate::parse_http_date(&last_modified).unwrap();
+
+        let since = |since| {
+            Request::get("/drivers")
+                .header("if-modified-since", httpdate::fmt_http_date(since))
+                .body(Default::default())
+                .unwrap()
+        };
+        let minute = Duration::from_secs(60);
+
+        for date in [last_modified, last_modified + minute] {
+            let res = testing::send(&state, since(date)).await;
+            assert_eq!(res.status, StatusCode::NOT_MODIFIED);
+            assert!(res.header("etag").is_some());
+        }
+
+        let res = testing::send(&state, since(last_modified - minute)).await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.body.as_array().map(Vec::len), Some(4));
+
+        // If-None-Match takes precedence.
+        let mut req = since(last_modified + minute);
+        req.headers_mut()
+            .insert("if-none-match", "\"stale\"".parse().unwrap());
+        let res = testing::send(&state, req).await;
+        assert_eq!(res.status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn refreshes_drivers_conditionally() {
+        static LAST_MODIFIED: &str = "Wed, 01 Jan 2025 00:00:00 GMT";
+
+        let drivers = routing::get(|headers: HeaderMap| async move {
+            if headers
+                .get("if-none-match")
+                .is_some_and(|etag| etag == "\"v1\"")
+            {
+                return StatusCode::NOT_MODIFIED.into_response();
+            }
+            let validators = [("etag", "\"v1\""), ("last-modified", LAST_MODIFIED)];
+            (validators, testing::DRIVERS).into_response()
+        });
+        let upstream = Upstream::start(Router::new().route("/_driverList.asp", drivers)).await;
+        let state = upstream.state([]);
+
+        let first = refresh_drivers(&state).await.unwrap();
+        let second = refresh_drivers(&state).await.unwrap();
+
+        let requests = upstream.requests_to("/_driverList.asp");
+        assert!(!requests[0].headers.contains_key("if-none-match"));
+        assert!(!requests[0].headers.contains_key("if-modified-since"));
+        assert_eq!(requests[1].headers["if-none-match"], "\"v1\"");
+        assert_eq!(requests[1].headers["if-modified-since"], LAST_MODIFIED);
+
+        // The cached list is kept, and considered fresh again.
+        assert_eq!(second.drivers.0.len(), 4);
+        assert_eq!(second.etag, first.etag);
+        assert_eq!(second.last_modified, first.last_modified);
+        assert!(second.fetched_at > first.fetched_at);
+        let cache = state.drivers.read().unwrap().clone().unwrap();
+        assert!(Arc::ptr_eq(&cache, &second));
+    }
+
+    #[tokio::test]
+    async fn wakes_pollers_when_drivers_change() {
+        let changed = Arc::new(AtomicBool::new(false));
+        let drivers = routing::get({
+            let changed = changed.clone();
+            move || async move {
+                if changed.load(Ordering::SeqCst) {
+                    r#"[{ "Name": "Canon TM-300", "Code": "CANON-TM300" }]"#
+                } else {
+                    testing::DRIVERS
+                }
+            }
+        });
+        let upstream = Upstream::start(Router::new().route("/_driverList.asp", drivers)).await;
+        let state = upstream.state([]);
+        let etag = cached_drivers(&state).await.unwrap().etag.clone();
+
+        let uri = format!("/drivers/poll?version={}", etag.trim_matches('"'));
+        let poll = testing::send(&state, testing::get(&uri));
+        let refresh = async {
+            time::sleep(Duration::from_millis(100)).await;
+            changed.store(true, Ordering::SeqCst);
+            refresh_drivers(&state).await.unwrap()
+        };
+        let (res, cache) = tokio::join!(poll, refresh);
+
+        assert_eq!(res.status, StatusCode::OK);
+        assert_ne!(cache.etag, etag);
+        assert_eq!(res.header("etag"), Some(cache.etag.as_str()));
+        assert_eq!(
+            res.body,
+            json!([{ "name": "Canon TM-300", "code": "CANON-TM300" }])
+        );
+    }
+
+    #[tokio::test]
+    async fn answers_unchanged_polls_with_304() {
+        let state = testing::state([("MOCK_MODE", "true"), ("DRIVERS_POLL_TIMEOUT", "1")]);
+        let cache = cached_drivers(&state).await.unwrap();
+
+        let started = Instant::now();
+        let uri = format!("/drivers/poll?version={}", cache.etag.trim_matches('"'));
+        let res = testing::send(&state, testing::get(&uri)).await;
+        assert!(started.elapsed() >= Duration::from_secs(1));
+
+        assert_eq!(res.status, StatusCode::NOT_MODIFIED);
+        assert_eq!(res.header("etag"), Some(cache.etag.as_str()));
+        let last_modified = httpdate::fmt_http_date(cache.last_modified);
+        assert_eq!(res.header("last-modified"), Some(last_modified.as_str()));
+
+        // An outdated version is answered right away.
+        let res = testing::send(&state, testing::get("/drivers/poll?version=outdated")).await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.body.as_array().map(Vec::len), Some(4));
+    }
+
+    #[tokio::test]
+    async fn maps_drivers_without_silent_collisions() {
+        let drivers = json!([
+            { "Name": "Epson", "Code": "EPSON" },
+            { "Name": "Epson (duplicate)", "Code": "EPSON" },
+            { "Name": "HP", "Code": "HP" },
+        ]);
+        let routes = Router::new().route(
+            "/_driverList.asp",
+            routing::get(move || async move { Json(drivers) }),
+        );
+        let upstream = Upstream::start(routes).await;
+
+        let state = upstream.state([]);
+        let res = testing::send(&state, testing::get("/drivers?shape=map")).await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.body, json!({ "EPSON": "Epson", "HP": "HP" }));
+        let list = testing::send(&state, testing::get("/drivers")).await;
+        assert_eq!(list.body.as_array().map(Vec::len), Some(2));
+
+        // The duplicate is dropped by the policy, and kept track of,
+        // rather than by the map.
+        let cache = state.drivers.read().unwrap().clone().unwrap();
+        let duplicates: Vec<_> = cache.duplicates.iter().map(|d| &d.name).collect();
+        assert_eq!(duplicates, ["Epson (duplicate)"]);
+
+        let state = upstream.state([("DRIVERS_DUPLICATES", "last-wins")]);
+        let res = testing::send(&state, testing::get("/drivers?shape=map")).await;
+        assert_eq!(
+            res.body,
+            json!({ "EPSON": "Epson (duplicate)", "HP": "HP" })
+        );
+
+        let state = upstream.state([("DRIVERS_DUPLICATES", "error")]);
+        let res = testing::send(&state, testing::get("/drivers?shape=map")).await;
+        assert_eq!(res.status, StatusCode::BAD_GATEWAY);
+    }
+
+    fn driver(name: &str, code: &str) -> Driver {
+        Driver {
+            name: name.into(),
+            code: code.into(),
+        }
+    }
+
+    #[test]
+    fn dedups_drivers_per_policy() {
+        let drivers = || {
+            Drivers(vec![
+                driver("Epson", "EPSON"),
+                driver("HP", "HP"),
+                driver("Epson (duplicate)", "EPSON"),
+            ])
+        };
+        let names = |drivers: &[Driver]| drivers.iter().map(|d| d.name.clone()).collect::<Vec<_>>();
+
+        let (kept, dropped) = dedup_drivers(drivers(), &DuplicateDrivers::FirstWins).unwrap();
+        assert_eq!(names(&kept.0), ["Epson", "HP"]);
+        assert_eq!(names(&dropped), ["Epson (duplicate)"]);
+
+        let (kept, dropped) = dedup_drivers(drivers(), &DuplicateDrivers::LastWins).unwrap();
+        assert_eq!(names(&kept.0), ["Epson (duplicate)", "HP"]);
+        assert_eq!(names(&dropped), ["Epson"]);
+
+        let Err(err) = dedup_drivers(drivers(), &DuplicateDrivers::Error) else {
+            panic!("duplicates should be rejected");
+        };
+        assert_eq!(
+            err.to_string(),
+            "upstream returned several drivers with code EPSON"
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_duplicates_to_admins() {
+        let drivers = json!([
+            { "Name": "Epson", "Code": "EPSON" },
+            { "Name": "Epson (duplicate)", "Code": "EPSON" },
+        ]);
+        let routes = Router::new().route(
+            "/_driverList.asp",
+            routing::get(move || async move { Json(drivers) }),
+        );
+        let upstream = Upstream::start(routes).await;
+        let state = upstream.state([("ADMIN_TOKEN", "t0ken")]);
+        cached_drivers(&state).await.unwrap();
+
+        let req = Request::get("/admin/cache")
+            .header("authorization", "Bearer t0ken")
+            .body(Default::default())
+            .unwrap();
+        let res = testing::send(&state, req).await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.body["drivers"]["entries"], 1);
+        assert_eq!(
+            res.body["drivers"]["duplicates"],
+            json!([{ "name": "Epson (duplicate)", "code": "EPSON" }])
+        );
+    }
+}
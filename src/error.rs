@@ -0,0 +1,107 @@
+use std::{fmt, time::Duration};
+
+use anyhow::anyhow;
+use axum::{
+    body::Body,
+    http::{header::RETRY_AFTER, Response, StatusCode},
+    response::IntoResponse,
+    BoxError, Json,
+};
+use serde::Serialize;
+use serde_json::{json, Value};
+use tower::timeout::error::Elapsed;
+
+/// Error answered as `{"error": "message"}`, optionally with some
+/// `details` about what went wrong.
+#[derive(Debug)]
+pub struct Error {
+    status: StatusCode,
+    err: anyhow::Error,
+    details: Option<Value>,
+    retry_after: Option<Duration>,
+}
+
+#[derive(Serialize)]
+pub struct FieldError {
+    /// Path to the invalid input, like `products.ABC123.qty`.
+    pub field: String,
+    pub message: String,
+}
+
+impl Error {
+    pub fn new(status: StatusCode, err: impl Into<anyhow::Error>) -> Self {
+        Self {
+            status,
+            err: err.into(),
+            details: None,
+            retry_after: None,
+        }
+    }
+
+    pub fn invalid(errors: Vec<FieldError>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, anyhow!("invalid request")).details(errors)
+    }
+
+    pub fn details(mut self, details: impl Serialize) -> Self {
+        self.details = serde_json::to_value(details).ok();
+        self
+    }
+
+    pub fn retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = Some(retry_after);
+        self
+    }
+}
+
+/// Clones only keep the message of the underlying error, not its
+/// source chain, which is enough to answer with.
+impl Clone for Error {
+    fn clone(&self) -> Self {
+        Self {
+            status: self.status,
+            err: anyhow!(self.err.to_string()),
+            details: self.details.clone(),
+            retry_after: self.retry_after,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.err.fmt(f)
+    }
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response<Body> {
+        let mut body = json!({ "error": self.err.to_string() });
+        if let Some(details) = self.details {
+            body["details"] = details;
+        }
+
+        let mut res = (self.status, Json(body)).into_response();
+
+        if let Some(retry_after) = self.retry_after {
+            // Retry-After only carries whole seconds, round up so that
+            // clients do not come back too early.
+            let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            res.headers_mut().insert(RETRY_AFTER, secs.into());
+        }
+
+        res
+    }
+}
+
+impl<E: Into<anyhow::Error>> From<E> for Error {
+    fn from(err: E) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, err)
+    }
+}
+
+pub async fn handle_timeout(err: BoxError) -> Error {
+    if err.is::<Elapsed>() {
+        Error::new(StatusCode::GATEWAY_TIMEOUT, anyhow!("request timed out"))
+    } else {
+        Error::new(StatusCode::INTERNAL_SERVER_ERROR, anyhow!(err))
+    }
+}
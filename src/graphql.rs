@@ -0,0 +1,114 @@
+use std::sync::Arc;
+
+use async_graphql::{
+    http::GraphiQLSource, Context, EmptyMutation, EmptySubscription, InputObject, Object, Request,
+    Response, Result, Schema,
+};
+use axum::{extract::State, response::Html, Json};
+
+use crate::{
+    drivers::{self, Driver},
+    prices::{self, Prices},
+    state::AppState,
+    upstream,
+};
+
+pub type GraphqlSchema = Schema<Query, EmptyMutation, EmptySubscription>;
+
+pub fn schema() -> GraphqlSchema {
+    Schema::new(Query, EmptyMutation, EmptySubscription)
+}
+
+#[derive(InputObject)]
+pub struct Product {
+    code: String,
+    /// Defaults to `DEFAULT_QUANTITY`.
+    qty: Option<usize>,
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    /// Lists the drivers, optionally keeping only the ones whose name
+    /// contains the given filter (case insensitive).
+    async fn drivers(&self, ctx: &Context<'_>, name: Option<String>) -> Result<Vec<Driver>> {
+        let state = ctx.data::<Arc<AppState>>()?;
+        let cache = drivers::cached_drivers(state).await?;
+        let name = name.map(|name| name.to_lowercase());
+
+        let drivers = cache
+            .drivers
+            .0
+            .iter()
+            .filter(|driver| match &name {
+                Some(name) => driver.name.to_lowercase().contains(name),
+                None => true,
+            })
+            .cloned()
+            .collect();
+
+        Ok(drivers)
+    }
+
+    /// Prices the given products.
+    async fn prices(
+        &self,
+        ctx: &Context<'_>,
+        products: Vec<Product>,
+        #[graphql(default = "EUR")] currency: String,
+    ) -> Result<Prices> {
+        let state = ctx.data::<Arc<AppState>>()?;
+        let default_qty = state.config().default_quantity;
+        let products = products
+            .into_iter()
+            .map(|p| (p.code, p.qty.unwrap_or(default_qty)))
+            .collect();
+        let upstream = ctx.data::<upstream::Context>()?;
+        let (prices, _) = prices::fetch_prices(state, upstream, products, &currency).await?;
+        Ok(prices)
+    }
+}
+
+pub async fn graphiql() -> Html<String> {
+    Html(GraphiQLSource::build().endpoint("/graphql").finish())
+}
+
+pub async fn graphql(
+    State(state): State<Arc<AppState>>,
+    ctx: upstream::Context,
+    Json(req): Json<Request>,
+) -> Json<Response> {
+    let schema = state.graphql.clone();
+    Json(schema.execute(req.data(state).data(ctx)).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    use crate::testing;
+
+    #[tokio::test]
+    async fn lists_drivers() {
+        let state = testing::state([("MOCK_MODE", "true")]);
+
+        let query = "{ drivers { code name } }";
+        let res = testing::send(&state, testing::post("/graphql", json!({ "query": query }))).await;
+        assert_eq!(res.status, StatusCode::OK);
+        let drivers = res.body["data"]["drivers"].as_array().unwrap();
+        assert_eq!(drivers.len(), 4);
+        assert_eq!(
+            drivers[0],
+            json!({ "code": "EPSON-SCP9500", "name": "Epson SureColor SC-P9500" })
+        );
+
+        let query = r#"{ drivers(name: "latex") { code } }"#;
+        let res = testing::send(&state, testing::post("/graphql", json!({ "query": query }))).await;
+        assert_eq!(
+            res.body["data"]["drivers"],
+            json!([{ "code": "HP-LATEX-800W" }])
+        );
+    }
+}
@@ -0,0 +1,233 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use axum::{extract::State, http::StatusCode, Json};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::{output, state::AppState, upstream};
+
+pub async fn health() -> Json<Value> {
+    Json(json!({ "status": "ok" }))
+}
+
+/// Answers 200 when upstream answers a cheap request within
+/// `READINESS_TIMEOUT`, 503 otherwise. The probe is sent once, without
+/// retries nor waiting for upstream permits, so that the answer comes
+/// promptly.
+pub async fn ready(State(state): State<Arc<AppState>>) -> (StatusCode, Json<Value>) {
+    if state.config().mock_mode {
+        return (StatusCode::OK, Json(json!({ "status": "ready" })));
+    }
+
+    let config = state.config();
+    let req = state
+        .client
+        .head(config.drivers_url.clone())
+        .timeout(config.readiness_timeout);
+    let req = match &config.upstream_api_key {
+        Some(key) => upstream::with_api_key(&config, req, key.expose()),
+        None => req,
+    };
+    let res = req.send().await;
+
+    let error = match res {
+        Ok(res) if res.status().is_server_error() => format!("upstream answered {}", res.status()),
+        Ok(_) => return (StatusCode::OK, Json(json!({ "status": "ready" }))),
+        Err(err) if err.is_timeout() => "upstream probe timed out".into(),
+        // The URL may carry credentials, from `UPSTREAM_BASE` or the
+        // API key sent as a query parameter, which must not end up in
+        // responses.
+        Err(err) => err.without_url().to_string(),
+    };
+
+    let body = json!({ "status": "unready", "error": error });
+    (StatusCode::SERVICE_UNAVAILABLE, Json(body))
+}
+
+#[derive(Serialize)]
+pub struct UpstreamStatus {
+    last_check_at: String,
+    reachable: bool,
+    error: Option<String>,
+    /// Seconds left before calls are sent again, when upstream asked us
+    /// to back off with a 429. There is no circuit breaker: this back
+    /// off is the only reason calls are held.
+    throttled_for_secs: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct DriversStatus {
+    fetched_at: String,
+    age_secs: u64,
+    /// Whether the background refresh kept up, allowing one missed
+    /// refresh.
+    fresh: bool,
+}
+
+#[derive(Serialize)]
+pub struct DetailedHealth {
+    healthy: bool,
+    /// Absent before the first upstream call.
+    upstream: Option<UpstreamStatus>,
+    /// Absent until drivers could be fetched once.
+    drivers: Option<DriversStatus>,
+    in_flight: usize,
+    max_in_flight: usize,
+    /// Why the startup self check failed, if it did.
+    degraded: Option<String>,
+}
+
+/// Reports the state of upstream and of the caches. Answers 503 when
+/// the last upstream call failed, when no drivers could be fetched yet
+/// or when the startup self check failed, with the details either way.
+/// Nothing reported is sensitive, upstream errors are stripped of their
+/// URL, so the report is not behind the admin token.
+pub async fn detailed(State(state): State<Arc<AppState>>) -> (StatusCode, Json<DetailedHealth>) {
+    let upstream = state.upstream_health.last().map(|check| UpstreamStatus {
+        last_check_at: output::format_rfc3339(check.at),
+        reachable: check.error.is_none(),
+        error: check.error,
+        throttled_for_secs: state.throttle.remaining().map(|d| d.as_secs()),
+    });
+
+    let drivers = state.drivers.read().unwrap().clone();
+    let drivers = drivers.map(|cache| {
+        let age = SystemTime::now()
+            .duration_since(cache.fetched_at)
+            .unwrap_or(Duration::ZERO);
+
+        DriversStatus {
+            fetched_at: output::format_rfc3339(cache.fetched_at),
+            age_secs: age.as_secs(),
+            fresh: age <= state.config().drivers_refresh_interval * 2,
+        }
+    });
+
+    let max_in_flight = state.max_in_flight;
+    let in_flight = max_in_flight.saturating_sub(state.in_flight.available_permits());
+
+    let degraded = state.degraded.read().unwrap().clone();
+    let healthy =
+        drivers.is_some() && degraded.is_none() && upstream.as_ref().map_or(true, |u| u.reachable);
+    let status = if healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    let health = DetailedHealth {
+        healthy,
+        upstream,
+        drivers,
+        in_flight,
+        max_in_flight,
+        degraded,
+    };
+
+    (status, Json(health))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use axum::{
+        http::{header::RETRY_AFTER, StatusCode},
+        routing, Router,
+    };
+    use tokio::time;
+
+    use crate::testing::{self, Upstream};
+
+    #[tokio::test]
+    async fn probes_upstream_with_the_api_key() {
+        let upstream = Upstream::start(testing::drivers()).await;
+        let state = upstream.state([("UPSTREAM_API_KEY", "s3cret")]);
+
+        let res = testing::send(&state, testing::get("/ready")).await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.body["status"], "ready");
+
+        let requests = upstream.requests_to("/_driverList.asp");
+        assert_eq!(requests[0].method, "HEAD");
+        assert_eq!(requests[0].headers["x-api-key"], "s3cret");
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_slow_upstreams() {
+        let slow = routing::get(|| async {
+            time::sleep(Duration::from_secs(10)).await;
+            testing::DRIVERS
+        });
+        let upstream = Upstream::start(Router::new().route("/_driverList.asp", slow)).await;
+        let state = upstream.state([("READINESS_TIMEOUT", "1")]);
+
+        let started = Instant::now();
+        let res = testing::send(&state, testing::get("/ready")).await;
+        assert!(started.elapsed() < Duration::from_secs(3));
+        assert_eq!(res.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.body["status"], "unready");
+        assert_eq!(res.body["error"], "upstream probe timed out");
+    }
+
+    #[tokio::test]
+    async fn reports_a_healthy_upstream() {
+        let upstream = Upstream::start(testing::fixtures()).await;
+        let state = upstream.state([]);
+
+        let res = testing::send(&state, testing::get("/health/detailed")).await;
+        assert_eq!(res.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.body["healthy"], false);
+        assert!(res.body["upstream"].is_null());
+        assert!(res.body["drivers"].is_null());
+
+        testing::send(&state, testing::get("/drivers")).await;
+        testing::send(&state, testing::get("/prices/EPSON-SCP9500")).await;
+
+        let res = testing::send(&state, testing::get("/health/detailed")).await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.body["healthy"], true);
+        assert_eq!(res.body["upstream"]["reachable"], true);
+        assert!(res.body["upstream"]["error"].is_null());
+        assert!(res.body["upstream"]["throttled_for_secs"].is_null());
+        assert_eq!(res.body["drivers"]["fresh"], true);
+        assert_eq!(res.body["in_flight"], 0);
+        assert_eq!(res.body["max_in_flight"], 256);
+    }
+
+    #[tokio::test]
+    async fn reports_a_degraded_upstream() {
+        let failing = routing::post(|| async { StatusCode::INTERNAL_SERVER_ERROR });
+        let upstream = Upstream::start(testing::drivers().route("/_prices.asp", failing)).await;
+        let state = upstream.state([]);
+
+        testing::send(&state, testing::get("/drivers")).await;
+        testing::send(&state, testing::get("/prices/EPSON-SCP9500")).await;
+
+        let res = testing::send(&state, testing::get("/health/detailed")).await;
+        assert_eq!(res.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.body["healthy"], false);
+        assert_eq!(res.body["upstream"]["reachable"], false);
+        assert_eq!(
+            res.body["upstream"]["error"],
+            "upstream answered 500 Internal Server Error"
+        );
+        assert_eq!(res.body["drivers"]["fresh"], true);
+
+        let throttling =
+            routing::post(|| async { (StatusCode::TOO_MANY_REQUESTS, [(RETRY_AFTER, "30")]) });
+        let upstream = Upstream::start(testing::drivers().route("/_prices.asp", throttling)).await;
+        let state = upstream.state([]);
+
+        testing::send(&state, testing::get("/drivers")).await;
+        testing::send(&state, testing::get("/prices/EPSON-SCP9500")).await;
+
+        let res = testing::send(&state, testing::get("/health/detailed")).await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.body["upstream"]["reachable"], true);
+        assert!(res.body["upstream"]["throttled_for_secs"].as_u64() > Some(25));
+    }
+}
@@ -1,38 +1,54 @@
-use std::{collections::HashMap, env};
+mod admin;
+mod cache;
+mod capabilities;
+mod catalog;
+mod config;
+mod dns;
+mod drivers;
+mod error;
+mod graphql;
+mod health;
+mod metrics;
+mod middleware;
+mod output;
+mod prefetch;
+mod prices;
+mod query;
+mod reload;
+mod self_check;
+mod state;
+#[cfg(test)]
+mod testing;
+mod upstream;
+mod validation;
+mod webhook;
+
+use std::{sync::Arc, time::Duration};
 
 use axum::{
     body::Body,
-    http::{HeaderValue, Method, Response, StatusCode},
-    response::IntoResponse,
+    error_handling::HandleErrorLayer,
+    http::{HeaderName, HeaderValue, Method},
     routing::{get, post},
-    Json, Router,
+    Router,
 };
-use reqwest::Client;
-use serde::{Deserialize, Serialize};
-use serde_json::json;
 use tokio::net::TcpListener;
-use tower_http::cors::{AllowHeaders, CorsLayer};
-use tracing::debug;
+use tower::ServiceBuilder;
+use tower_http::{
+    cors::{AllowHeaders, AllowOrigin, CorsLayer},
+    decompression::RequestDecompressionLayer,
+    map_request_body::MapRequestBodyLayer,
+    set_header::SetResponseHeaderLayer,
+    trace::TraceLayer,
+};
+use tracing::{debug, warn};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
-static LIST_DRIVERS_URL: &str =
-    "https://order.printfactory.cloud/PF/_driverList.asp?Product=PrintFactory";
-
-static GET_PRICES_URL: &str = "https://order.printfactory.cloud/PF/_prices.asp";
-
-struct Error(anyhow::Error);
-
-impl IntoResponse for Error {
-    fn into_response(self) -> Response<Body> {
-        (StatusCode::INTERNAL_SERVER_ERROR, self.0.to_string()).into_response()
-    }
-}
+use crate::{config::Config, state::AppState};
 
-impl<E: Into<anyhow::Error>> From<E> for Error {
-    fn from(err: E) -> Self {
-        Self(err.into())
-    }
-}
+/// Advertises the request timeout, so that clients can set theirs
+/// slightly higher.
+static SERVER_TIMEOUT_MS: &str = "x-server-timeout-ms";
 
 #[tokio::main]
 async fn main() {
@@ -41,29 +57,47 @@ async fn main() {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    let host = match env::var("HOST") {
-        Ok(host) => host,
-        Err(_) => "localhost".into(),
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(err) => panic!("invalid configuration: {err}"),
     };
+    debug!("loaded {config:?}");
+    let host = config.host.clone();
+    let port = config.port;
 
-    let port = match env::var("PORT") {
-        Ok(port) => port.parse().expect("should be an unsigned integer"),
-        Err(_) => 3000,
-    };
+    let state = Arc::new(AppState::new(config));
 
-    let cors = CorsLayer::new()
-        .allow_origin([
-            HeaderValue::from_static("http://localhost:3000"),
-            HeaderValue::from_static("https://app.ripee.fr"),
-            HeaderValue::from_static("https://bolt.new"),
-        ])
-        .allow_headers(AllowHeaders::any())
-        .allow_methods([Method::GET, Method::POST]);
+    debug!(
+        "using upstream endpoints {} and {}",
+        state.config().drivers_url,
+        state.config().prices_url
+    );
 
-    let app = Router::new()
-        .route("/drivers", get(list_drivers))
-        .route("/prices", post(get_prices))
-        .layer(cors);
+    if state.config().mock_mode {
+        warn!("mock mode enabled, serving fixtures instead of calling upstream");
+    }
+
+    #[cfg(feature = "fault-injection")]
+    warn!(
+        "fault injection compiled in, failing {} of upstream calls",
+        state.config().test_failure_rate
+    );
+
+    if state.config().log_bodies {
+        warn!("logging request and response bodies, do not enable in production");
+    }
+
+    if state.config().self_check {
+        self_check::run(&state).await;
+    }
+
+    if state.config().warm_up {
+        upstream::warm_up(&state, state.config().drivers_url.clone()).await;
+    }
+    drivers::spawn_drivers_refresh(state.clone());
+    cache::spawn_prices_sweeper(state.clone());
+    prefetch::spawn_prefetcher(state.clone());
+    reload::spawn_reloader(state.clone());
 
     debug!("starting server {host} at port {port}…");
 
@@ -71,102 +105,152 @@ async fn main() {
         .await
         .expect("should start TCP listener");
 
-    axum::serve(listener, app)
+    axum::serve(listener, app(state))
         .await
         .expect("should start TCP server")
 }
 
-#[derive(Serialize, Deserialize)]
-#[serde(transparent)]
-struct Drivers(Vec<Driver>);
+fn app(state: Arc<AppState>) -> Router {
+    let request_timeout = state.startup_config().request_timeout;
 
-#[derive(Serialize, Deserialize)]
-#[serde(rename_all(deserialize = "PascalCase"))]
-struct Driver {
-    name: String,
-    code: String,
-}
+    let server_timeout = SetResponseHeaderLayer::overriding(
+        HeaderName::from_static(SERVER_TIMEOUT_MS),
+        HeaderValue::from(request_timeout.as_millis() as u64),
+    );
 
-async fn list_drivers() -> Result<Json<Drivers>, Error> {
-    let res = reqwest::get(LIST_DRIVERS_URL).await?;
-    let bytes = res.bytes().await?;
-    let drivers: Drivers = serde_json::from_slice(&bytes.slice(..))?;
-    Ok(Json(drivers))
-}
+    let timeout = ServiceBuilder::new()
+        .layer(HandleErrorLayer::new(error::handle_timeout))
+        .timeout(request_timeout);
 
-#[derive(Serialize, Deserialize)]
-#[serde(rename_all = "PascalCase")]
-enum Plan {
-    Connect,
-    Production,
-    #[serde(other)]
-    Other,
-}
+    // Origins are read on every request, so that CORS_ORIGINS can be
+    // reloaded.
+    let origins = state.clone();
+    let cors = CorsLayer::new()
+        .allow_origin(AllowOrigin::predicate(move |origin, _| {
+            let origins = &origins.config().cors_origins;
+            origins.iter().any(|o| o.as_bytes() == origin.as_bytes())
+        }))
+        .allow_headers(AllowHeaders::any())
+        .allow_methods([Method::GET, Method::POST])
+        .expose_headers([HeaderName::from_static(SERVER_TIMEOUT_MS)])
+        .max_age(Duration::from_secs(3600));
 
-#[derive(Serialize, Deserialize)]
-#[serde(rename_all = "PascalCase")]
-struct GetPricesResponse {
-    r#type: String,
-    results: Vec<(Plan, i16, f32, f32)>,
-}
+    let admin = Router::new()
+        .route("/cache", get(admin::cache))
+        .route("/config", get(admin::config))
+        .route("/upstream", get(admin::upstream))
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            admin::require_token,
+        ));
 
-#[derive(Serialize, Deserialize)]
-#[serde(transparent)]
-struct Products(HashMap<String, usize>);
+    // Decompressed bodies go through the usual body limit, which bounds
+    // what a small compressed body can expand to. Unsupported encodings
+    // are answered with a 415.
+    let decompression = ServiceBuilder::new()
+        .layer(RequestDecompressionLayer::new())
+        .layer(MapRequestBodyLayer::new(Body::new));
 
-#[derive(Default, Serialize, Deserialize)]
-struct Prices {
-    yearly: PlanPrice,
-    monthly: PlanPrice,
-}
+    let upstream_bound = Router::new()
+        .route("/prices", post(prices::get_prices).layer(decompression))
+        .route("/prices/raw", post(prices::get_raw_prices))
+        .route("/prices/compare", post(prices::compare_prices))
+        .route("/prices/validate", post(prices::validate_prices))
+        .route("/prices/{code}", get(prices::get_product_prices))
+        .route("/graphql", post(graphql::graphql))
+        .route("/catalog", post(catalog::catalog))
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            middleware::shed_load,
+        ));
 
-#[derive(Default, Serialize, Deserialize)]
-struct PlanPrice {
-    connect: usize,
-    production: usize,
+    Router::new()
+        .route("/capabilities", get(capabilities::capabilities))
+        .route("/health", get(health::health))
+        .route("/ready", get(health::ready))
+        .route("/health/detailed", get(health::detailed))
+        .route("/drivers", get(drivers::list_drivers))
+        .route("/drivers/diff", post(drivers::diff_drivers))
+        .route("/graphql", get(graphql::graphiql))
+        .merge(upstream_bound)
+        .route("/metrics", get(metrics::metrics))
+        .route("/slo", get(metrics::slo))
+        .nest("/admin", admin)
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            middleware::log_bodies,
+        ))
+        .layer(timeout)
+        // Long polls are bounded by DRIVERS_POLL_TIMEOUT rather than by
+        // the request timeout.
+        .route("/drivers/poll", get(drivers::poll_drivers))
+        // Outside the timeout, so that timed out requests still count
+        // against their route latency target.
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            middleware::track_latency,
+        ))
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(middleware::SampledSpan(
+                    state.startup_config().log_sample_rate,
+                ))
+                .on_request(())
+                .on_response(middleware::SampledOnResponse),
+        )
+        .layer(server_timeout)
+        // Keep CORS as the outermost layer: preflight requests are then
+        // answered right away, without reaching any other layer nor
+        // handler. Only the Allow header is added on top of it.
+        .layer(cors)
+        .layer(axum::middleware::from_fn(middleware::allow))
+        .with_state(state)
 }
 
-async fn get_prices(Json(products): Json<Products>) -> Result<Json<Prices>, Error> {
-    let products = products.0.into_iter().collect::<Vec<_>>();
-    let res = Client::new()
-        .post(GET_PRICES_URL)
-        .body(
-            json!({
-                "Product": "PrintFactory",
-                "Currency": "EUR",
-                "Products": products,
-                "Country": "",
-                "Dealer": null,
-            })
-            .to_string(),
-        )
-        .send()
-        .await?;
-    let bytes = res.bytes().await?;
-    let res: GetPricesResponse = serde_json::from_slice(&bytes.slice(..))?;
-
-    let prices = res
-        .results
-        .into_iter()
-        .fold(Prices::default(), |mut prices, (plan, a, _b, c)| {
-            match plan {
-                Plan::Connect if a == 30 => {
-                    prices.monthly.connect = (c * 100.0).round() as usize;
-                }
-                Plan::Connect if a == 365 => {
-                    prices.yearly.connect = ((c / 12.0) * 100.0).round() as usize;
-                }
-                Plan::Production if a == 30 => {
-                    prices.monthly.production = (c * 100.0).round() as usize;
-                }
-                Plan::Production if a == 365 => {
-                    prices.yearly.production = ((c / 12.0) * 100.0).round() as usize;
-                }
-                Plan::Production => {}
-                _ => {}
-            };
-            prices
-        });
-
-    Ok(Json(prices))
+#[cfg(test)]
+mod tests {
+    use axum::http::{Request, StatusCode};
+    use serde_json::json;
+
+    use crate::testing::{self, Upstream};
+
+    #[tokio::test]
+    async fn answers_preflights_without_calling_upstream() {
+        let upstream = Upstream::start(testing::fixtures()).await;
+        let state = upstream.state([]);
+
+        let req = Request::options("/prices")
+            .header("origin", "https://app.ripee.fr")
+            .header("access-control-request-method", "POST")
+            .header("access-control-request-headers", "content-type")
+            .body(Default::default())
+            .unwrap();
+        let res = testing::send(&state, req).await;
+
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(
+            res.header("access-control-allow-origin"),
+            Some("https://app.ripee.fr")
+        );
+        let methods = res.header("access-control-allow-methods").unwrap();
+        assert!(methods.contains("POST"), "{methods}");
+        assert!(upstream.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn advertises_the_request_timeout() {
+        let state = testing::state([("MOCK_MODE", "true"), ("REQUEST_TIMEOUT", "7")]);
+
+        let requests = [
+            testing::get("/health"),
+            testing::post("/prices", json!({ "EPSON-SCP9500": 1 })),
+            testing::post("/prices", json!({})),
+            testing::get("/unknown"),
+        ];
+        for req in requests {
+            let uri = req.uri().clone();
+            let res = testing::send(&state, req).await;
+            assert_eq!(res.header("x-server-timeout-ms"), Some("7000"), "{uri}");
+        }
+    }
 }
@@ -0,0 +1,219 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::State,
+    http::header::{CACHE_CONTROL, CONTENT_TYPE},
+    response::IntoResponse,
+    Json,
+};
+use serde::Serialize;
+
+use crate::{config::Config, state::AppState};
+
+/// Width of the buckets latencies are counted in.
+const SLO_BUCKET: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+pub struct Metrics {
+    pub prices_cache_swept_total: AtomicU64,
+    pub prices_cache_last_sweep: AtomicU64,
+    pub slo: Slo,
+}
+
+struct SloBucket {
+    started_at: Instant,
+    total: u64,
+    good: u64,
+}
+
+/// Counts, for each route with a latency target, the requests that
+/// met it over the last `SLO_WINDOW`.
+#[derive(Default)]
+pub struct Slo(Mutex<HashMap<String, VecDeque<SloBucket>>>);
+
+#[derive(Serialize)]
+pub struct SloReport {
+    route: String,
+    target_ms: u64,
+    total: u64,
+    good: u64,
+    /// Fraction of requests that met the target, absent when no request
+    /// was made during the window.
+    ratio: Option<f64>,
+}
+
+impl Slo {
+    pub fn record(&self, config: &Config, route: &str, elapsed: Duration) {
+        let Some(target) = config.slo_targets.get(route) else {
+            return;
+        };
+
+        let now = Instant::now();
+        let mut routes = self.0.lock().unwrap();
+        let buckets = routes.entry(route.to_owned()).or_default();
+        prune(buckets, config.slo_window, now);
+
+        match buckets.back_mut() {
+            Some(bucket) if now.duration_since(bucket.started_at) < SLO_BUCKET => bucket.total += 1,
+            _ => buckets.push_back(SloBucket {
+                started_at: now,
+                total: 1,
+                good: 0,
+            }),
+        }
+
+        if elapsed <= Duration::from_millis(*target) {
+            if let Some(bucket) = buckets.back_mut() {
+                bucket.good += 1;
+            }
+        }
+    }
+
+    pub fn report(&self, config: &Config) -> Vec<SloReport> {
+        let now = Instant::now();
+        let mut routes = self.0.lock().unwrap();
+
+        let mut reports: Vec<SloReport> = config
+            .slo_targets
+            .iter()
+            .map(|(route, target)| {
+                let (total, good) = match routes.get_mut(route) {
+                    Some(buckets) => {
+                        prune(buckets, config.slo_window, now);
+                        buckets
+                            .iter()
+                            .fold((0, 0), |(t, g), b| (t + b.total, g + b.good))
+                    }
+                    None => (0, 0),
+                };
+
+                SloReport {
+                    route: route.clone(),
+                    target_ms: *target,
+                    total,
+                    good,
+                    ratio: (total > 0).then(|| good as f64 / total as f64),
+                }
+            })
+            .collect();
+
+        reports.sort_by(|a, b| a.route.cmp(&b.route));
+        reports
+    }
+}
+
+fn prune(buckets: &mut VecDeque<SloBucket>, window: Duration, now: Instant) {
+    while let Some(bucket) = buckets.front() {
+        if now.duration_since(bucket.started_at) < window {
+            break;
+        }
+        buckets.pop_front();
+    }
+}
+
+impl Metrics {
+    /// Renders metrics in the Prometheus text exposition format.
+    fn render(&self, config: &Config) -> String {
+        let mut out = String::new();
+
+        let swept = self.prices_cache_swept_total.load(Ordering::Relaxed);
+        let _ = writeln!(
+            out,
+            "# HELP prices_cache_swept_total Expired prices entries removed by the sweeper."
+        );
+        let _ = writeln!(out, "# TYPE prices_cache_swept_total counter");
+        let _ = writeln!(out, "prices_cache_swept_total {swept}");
+
+        let last = self.prices_cache_last_sweep.load(Ordering::Relaxed);
+        let _ = writeln!(
+            out,
+            "# HELP prices_cache_last_sweep Expired prices entries removed by the last sweep."
+        );
+        let _ = writeln!(out, "# TYPE prices_cache_last_sweep gauge");
+        let _ = writeln!(out, "prices_cache_last_sweep {last}");
+
+        let _ = writeln!(
+            out,
+            "# HELP slo_success_ratio Fraction of requests meeting the route latency target."
+        );
+        let _ = writeln!(out, "# TYPE slo_success_ratio gauge");
+        for report in self.slo.report(config) {
+            if let Some(ratio) = report.ratio {
+                let route = report.route;
+                let _ = writeln!(out, "slo_success_ratio{{route=\"{route}\"}} {ratio}");
+            }
+        }
+
+        out
+    }
+}
+
+pub async fn metrics(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let content_type = "text/plain; version=0.0.4";
+    let body = state.metrics.render(&state.config());
+    ([(CONTENT_TYPE, content_type)], body)
+}
+
+pub async fn slo(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let reports = state.metrics.slo.report(&state.config());
+    ([(CACHE_CONTROL, "no-store")], Json(reports))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::json;
+    use tokio::time;
+
+    use crate::testing;
+
+    use super::Slo;
+
+    #[tokio::test]
+    async fn reports_slo_compliance_over_the_window() {
+        let targets = r#"{"/prices": 200, "/drivers": 100}"#;
+        let config = testing::config([("SLO_TARGETS", targets), ("SLO_WINDOW", "1")]);
+        let slo = Slo::default();
+
+        for ms in [50, 150, 200, 201] {
+            slo.record(&config, "/prices", Duration::from_millis(ms));
+        }
+        slo.record(&config, "/capabilities", Duration::from_secs(10));
+
+        let reports = serde_json::to_value(slo.report(&config)).unwrap();
+        let expected = json!([
+            { "route": "/drivers", "target_ms": 100, "total": 0, "good": 0, "ratio": null },
+            { "route": "/prices", "target_ms": 200, "total": 4, "good": 3, "ratio": 0.75 },
+        ]);
+        assert_eq!(reports, expected);
+
+        time::sleep(Duration::from_millis(1100)).await;
+        slo.record(&config, "/prices", Duration::from_millis(500));
+
+        let reports = serde_json::to_value(slo.report(&config)).unwrap();
+        assert_eq!(reports[1]["total"], 1);
+        assert_eq!(reports[1]["ratio"], 0.0);
+    }
+
+    #[tokio::test]
+    async fn tracks_route_latencies() {
+        let state = testing::state([("SLO_TARGETS", r#"{"/capabilities": 60000}"#)]);
+
+        testing::send(&state, testing::get("/capabilities")).await;
+        testing::send(&state, testing::get("/capabilities")).await;
+
+        let res = testing::send(&state, testing::get("/slo")).await;
+        assert_eq!(res.body[0]["route"], "/capabilities");
+        assert_eq!(res.body[0]["total"], 2);
+        assert_eq!(res.body[0]["ratio"], 1.0);
+    }
+}
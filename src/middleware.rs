@@ -0,0 +1,260 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::{MatchedPath, Request, State},
+    http::{self, header::ALLOW, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tower_http::trace::{MakeSpan, OnResponse};
+use tracing::{error_span, info, info_span, trace, Level, Span};
+
+use crate::{error::Error, state::AppState};
+
+/// Bodies bigger than that are truncated in logs.
+const MAX_LOGGED_BODY: usize = 4096;
+
+/// Same as the axum default body limit.
+const MAX_BUFFERED_BODY: usize = 2 * 1024 * 1024;
+
+/// Suggested to clients whose request was shed.
+const SHED_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Methods advertised in the `Allow` header of `OPTIONS` responses, by
+/// path.
+static ALLOWED_METHODS: [(&str, &str); 2] = [
+    ("/drivers", "GET, HEAD, OPTIONS"),
+    ("/prices", "POST, OPTIONS"),
+];
+
+fn truncate(bytes: &[u8]) -> String {
+    let len = bytes.len().min(MAX_LOGGED_BODY);
+    let mut body = String::from_utf8_lossy(&bytes[..len]).into_owned();
+    if bytes.len() > len {
+        body.push('…');
+    }
+    body
+}
+
+/// Creates the access log span of a `LOG_SAMPLE_RATE` fraction of the
+/// requests. The others get an error-level span, which
+/// [`SampledOnResponse`] does not log: failures logged by the trace
+/// layer then still carry the method and URI of their request.
+#[derive(Clone)]
+pub struct SampledSpan(pub f64);
+
+impl<B> MakeSpan<B> for SampledSpan {
+    fn make_span(&mut self, req: &http::Request<B>) -> Span {
+        if rand::random::<f64>() >= self.0 {
+            return error_span!(
+                "request",
+                method = %req.method(),
+                uri = %req.uri(),
+                version = ?req.version(),
+            );
+        }
+
+        info_span!(
+            "request",
+            method = %req.method(),
+            uri = %req.uri(),
+            version = ?req.version(),
+        )
+    }
+}
+
+/// Logs the response of sampled requests, see [`SampledSpan`].
+#[derive(Clone)]
+pub struct SampledOnResponse;
+
+impl<B> OnResponse<B> for SampledOnResponse {
+    fn on_response(self, res: &http::Response<B>, latency: Duration, span: &Span) {
+        let sampled = span
+            .metadata()
+            .is_some_and(|meta| *meta.level() == Level::INFO);
+        if !sampled {
+            return;
+        }
+
+        info!(
+            status = res.status().as_u16(),
+            latency = ?latency,
+            "finished processing request"
+        );
+    }
+}
+
+/// Logs request and response bodies at trace level when
+/// `LOG_BODIES` is enabled.
+pub async fn log_bodies(State(state): State<Arc<AppState>>, req: Request, next: Next) -> Response {
+    if !state.config().log_bodies {
+        return next.run(req).await;
+    }
+
+    let (parts, body) = req.into_parts();
+    let Ok(bytes) = to_bytes(body, MAX_BUFFERED_BODY).await else {
+        return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+    };
+    trace!(
+        "{} {} request body: {}",
+        parts.method,
+        parts.uri,
+        truncate(&bytes)
+    );
+
+    let uri = parts.uri.clone();
+    let res = next
+        .run(Request::from_parts(parts, Body::from(bytes)))
+        .await;
+
+    let (parts, body) = res.into_parts();
+    let Ok(bytes) = to_bytes(body, usize::MAX).await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    trace!("{uri} response body: {}", truncate(&bytes));
+
+    Response::from_parts(parts, Body::from(bytes))
+}
+
+/// Measures how long matched routes take to answer, for the SLO
+/// tracking.
+pub async fn track_latency(
+    State(state): State<Arc<AppState>>,
+    route: Option<MatchedPath>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(route) = route else {
+        return next.run(req).await;
+    };
+
+    let started_at = Instant::now();
+    let res = next.run(req).await;
+    let elapsed = started_at.elapsed();
+    state
+        .metrics
+        .slo
+        .record(&state.config(), route.as_str(), elapsed);
+    res
+}
+
+/// Rejects requests with a 503 when `MAX_IN_FLIGHT` of them are already
+/// being handled, rather than queuing work that would time out anyway.
+pub async fn shed_load(
+    State(state): State<Arc<AppState>>,
+    req: Request,
+    next: Next,
+) -> Result<Response, Error> {
+    let Ok(_permit) = state.in_flight.try_acquire() else {
+        let err = anyhow!("server is overloaded");
+        return Err(Error::new(StatusCode::SERVICE_UNAVAILABLE, err).retry_after(SHED_RETRY_AFTER));
+    };
+
+    Ok(next.run(req).await)
+}
+
+/// Adds the methods supported by the route to `OPTIONS` responses, for
+/// tooling introspecting it. Since the CORS layer answers every
+/// `OPTIONS` request, this must wrap it.
+pub async fn allow(req: Request, next: Next) -> Response {
+    let methods = ALLOWED_METHODS
+        .iter()
+        .find(|(path, _)| req.method() == Method::OPTIONS && *path == req.uri().path())
+        .map(|(_, methods)| HeaderValue::from_static(methods));
+
+    let mut res = next.run(req).await;
+    if let Some(methods) = methods {
+        res.headers_mut().insert(ALLOW, methods);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        http::{Request, StatusCode},
+        routing,
+    };
+    use serde_json::json;
+
+    use crate::testing::{self, Logs, Upstream};
+
+    #[tokio::test]
+    async fn sheds_load_when_saturated() {
+        let state = testing::state([("MOCK_MODE", "true"), ("MAX_IN_FLIGHT", "1")]);
+        let basket = json!({ "EPSON-SCP9500": 1 });
+
+        let permit = state.in_flight.try_acquire().unwrap();
+        let res = testing::send(&state, testing::post("/prices", basket.clone())).await;
+        assert_eq!(res.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.header("retry-after"), Some("1"));
+        assert_eq!(res.body["error"], "server is overloaded");
+
+        // Routes not calling upstream are never shed.
+        let res = testing::send(&state, testing::get("/health")).await;
+        assert_eq!(res.status, StatusCode::OK);
+
+        drop(permit);
+        let res = testing::send(&state, testing::post("/prices", basket)).await;
+        assert_eq!(res.status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn logs_errors_whatever_the_sampling() {
+        let (logs, _guard) = Logs::capture();
+
+        let failing = routing::post(|| async { StatusCode::INTERNAL_SERVER_ERROR });
+        let upstream = Upstream::start(testing::drivers().route("/_prices.asp", failing)).await;
+        let state = upstream.state([("LOG_SAMPLE_RATE", "0")]);
+
+        let res = testing::send(&state, testing::get("/drivers")).await;
+        assert_eq!(res.status, StatusCode::OK);
+        let res = testing::send(&state, testing::get("/prices/EPSON-SCP9500")).await;
+        assert_eq!(res.status, StatusCode::BAD_GATEWAY);
+
+        let logs = logs.contents();
+        assert!(!logs.contains("finished processing request"), "{logs}");
+        assert!(!logs.contains("uri=/drivers"), "{logs}");
+        let failure = logs
+            .lines()
+            .find(|line| line.contains("response failed"))
+            .unwrap_or_else(|| panic!("the failure should be logged: {logs}"));
+        assert!(failure.contains("method=GET"), "{failure}");
+        assert!(failure.contains("uri=/prices/EPSON-SCP9500"), "{failure}");
+    }
+
+    #[tokio::test]
+    async fn advertises_allowed_methods_per_route() {
+        let state = testing::state([("MOCK_MODE", "true")]);
+        let options = |uri: &str| Request::options(uri).body(Default::default()).unwrap();
+
+        let res = testing::send(&state, options("/drivers")).await;
+        assert_eq!(res.header("allow"), Some("GET, HEAD, OPTIONS"));
+        let res = testing::send(&state, options("/prices")).await;
+        assert_eq!(res.header("allow"), Some("POST, OPTIONS"));
+
+        // Alongside the CORS headers of preflight requests.
+        let req = Request::options("/prices")
+            .header("origin", "https://app.ripee.fr")
+            .header("access-control-request-method", "POST")
+            .body(Default::default())
+            .unwrap();
+        let res = testing::send(&state, req).await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.header("allow"), Some("POST, OPTIONS"));
+        assert_eq!(
+            res.header("access-control-allow-origin"),
+            Some("https://app.ripee.fr")
+        );
+
+        // Other methods are left alone.
+        let res = testing::send(&state, testing::get("/drivers")).await;
+        assert_eq!(res.header("allow"), None);
+    }
+}
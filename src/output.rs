@@ -0,0 +1,428 @@
+use std::time::SystemTime;
+
+use axum::{
+    extract::FromRequestParts,
+    http::{header::ACCEPT_LANGUAGE, request::Parts, HeaderMap},
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    config::{Config, UnknownFields},
+    error::{Error, FieldError},
+    query::{self, Query},
+};
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// Prices as integer cents.
+    #[default]
+    Cents,
+    /// Prices as strings formatted for the locale negotiated through the
+    /// `Accept-Language` header.
+    Localized,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Case {
+    /// Keys as serialized, like `fetched_at`.
+    #[default]
+    Snake,
+    /// Keys following the upstream convention, like `FetchedAt`.
+    Pascal,
+}
+
+#[derive(Clone, Copy, Default)]
+pub enum Locale {
+    #[default]
+    En,
+    Fr,
+}
+
+impl Locale {
+    fn parse(tag: &str) -> Option<Self> {
+        let lang = tag.split('-').next()?.trim();
+
+        if lang.eq_ignore_ascii_case("en") {
+            Some(Self::En)
+        } else if lang.eq_ignore_ascii_case("fr") {
+            Some(Self::Fr)
+        } else {
+            None
+        }
+    }
+
+    /// Picks the supported locale with the highest quality value from
+    /// the `Accept-Language` header, falling back to english.
+    fn negotiate(headers: &HeaderMap) -> Self {
+        let Some(langs) = headers.get(ACCEPT_LANGUAGE).and_then(|h| h.to_str().ok()) else {
+            return Self::default();
+        };
+
+        let mut langs: Vec<(f32, Self)> = langs
+            .split(',')
+            .filter_map(|lang| {
+                let mut params = lang.split(';');
+                let locale = Self::parse(params.next()?)?;
+                let q = params
+                    .find_map(|param| param.trim().strip_prefix("q="))
+                    .and_then(|q| q.parse().ok())
+                    .unwrap_or(1.0);
+                Some((q, locale))
+            })
+            .collect();
+
+        langs.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+        langs.first().map(|(_, locale)| *locale).unwrap_or_default()
+    }
+
+    fn decimal_separator(self) -> char {
+        match self {
+            Self::En => '.',
+            Self::Fr => ',',
+        }
+    }
+
+    /// Separates groups of thousands, a narrow no-break space in french.
+    fn grouping_separator(self) -> char {
+        match self {
+            Self::En => ',',
+            Self::Fr => '\u{202f}',
+        }
+    }
+
+    pub fn format_cents(self, cents: i64) -> String {
+        let sign = if cents < 0 { "-" } else { "" };
+        let cents = cents.unsigned_abs();
+
+        let units = (cents / 100).to_string();
+        let mut grouped = String::with_capacity(units.len() * 2);
+        for (i, digit) in units.chars().enumerate() {
+            if i > 0 && (units.len() - i) % 3 == 0 {
+                grouped.push(self.grouping_separator());
+            }
+            grouped.push(digit);
+        }
+
+        let sep = self.decimal_separator();
+        format!("{sign}{grouped}{sep}{:02}", cents % 100)
+    }
+}
+
+#[derive(Deserialize)]
+struct OutputQuery {
+    #[serde(default, deserialize_with = "query::last")]
+    format: Format,
+    #[serde(default, deserialize_with = "query::last")]
+    case: Case,
+    #[serde(default, deserialize_with = "query::last")]
+    summarize: Option<bool>,
+    /// Optional fields to add to the response, comma-separated and/or
+    /// repeated.
+    #[serde(default)]
+    include: Vec<String>,
+    /// Fields to restrict the response to, comma-separated and/or
+    /// repeated.
+    #[serde(default)]
+    fields: Vec<String>,
+}
+
+/// Options shaping how responses are serialized, extracted from the
+/// query string and the request headers.
+pub struct Output {
+    pub format: Format,
+    pub case: Case,
+    /// Whether prices are summarized as monthly and yearly prices per
+    /// plan, or only returned as the full tiers map.
+    pub summarize: bool,
+    pub locale: Locale,
+    pub include: Vec<String>,
+    /// Fields to keep in the response, all of them when unset.
+    pub fields: Option<Vec<String>>,
+}
+
+impl Output {
+    pub fn includes(&self, field: &str) -> bool {
+        self.include.iter().any(|include| include == field)
+    }
+
+    /// Rejects requested fields missing from `known`, unless
+    /// `UNKNOWN_FIELDS` is set to `ignore`.
+    pub fn check_fields(&self, config: &Config, known: &[&str]) -> Result<(), Error> {
+        let (UnknownFields::Reject, Some(fields)) = (&config.unknown_fields, &self.fields) else {
+            return Ok(());
+        };
+
+        let errors: Vec<_> = fields
+            .iter()
+            .filter(|field| !known.contains(&field.as_str()))
+            .map(|field| FieldError {
+                field: format!("fields.{field}"),
+                message: "should be a known field".into(),
+            })
+            .collect();
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::invalid(errors))
+        }
+    }
+
+    /// Drops the keys of the given object that were not requested, if
+    /// fields were requested at all.
+    pub fn select_fields(&self, value: &mut Value) {
+        let (Some(fields), Value::Object(object)) = (&self.fields, value) else {
+            return;
+        };
+
+        object.retain(|key, _| fields.contains(key));
+    }
+
+    /// Formats every price nested in the given objects according to
+    /// the requested format.
+    pub fn format_prices(&self, value: &mut Value, keys: &[&str]) {
+        let Format::Localized = self.format else {
+            return;
+        };
+
+        for key in keys {
+            if let Some(prices) = value.get_mut(*key) {
+                self.localize(prices);
+            }
+        }
+    }
+
+    /// Renames the keys of the given value and of every object nested
+    /// in it according to the requested case.
+    pub fn apply_case(&self, value: &mut Value) {
+        let Case::Pascal = self.case else {
+            return;
+        };
+
+        pascalize(value);
+    }
+
+    fn localize(&self, value: &mut Value) {
+        match value {
+            Value::Number(cents) => {
+                if let Some(cents) = cents.as_i64() {
+                    *value = Value::String(self.locale.format_cents(cents));
+                }
+            }
+            Value::Object(prices) => prices.values_mut().for_each(|v| self.localize(v)),
+            _ => {}
+        }
+    }
+}
+
+fn pascalize(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            *map = std::mem::take(map)
+                .into_iter()
+                .map(|(key, mut value)| {
+                    pascalize(&mut value);
+                    (to_pascal_case(&key), value)
+                })
+                .collect();
+        }
+        Value::Array(values) => values.iter_mut().for_each(pascalize),
+        _ => {}
+    }
+}
+
+fn to_pascal_case(key: &str) -> String {
+    key.split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Output {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Query(query) = Query::<OutputQuery>::from_request_parts(parts, state).await?;
+
+        let include = split_list(&query.include);
+        let fields = Some(split_list(&query.fields)).filter(|fields| !fields.is_empty());
+
+        Ok(Self {
+            format: query.format,
+            case: query.case,
+            summarize: query.summarize.unwrap_or(true),
+            locale: Locale::negotiate(&parts.headers),
+            include,
+            fields,
+        })
+    }
+}
+
+/// Flattens the values of a comma-separated and/or repeated parameter.
+fn split_list(values: &[String]) -> Vec<String> {
+    values
+        .iter()
+        .flat_map(|value| value.split(','))
+        .map(|value| value.trim().to_owned())
+        .filter(|value| !value.is_empty())
+        .collect()
+}
+
+/// Wraps the given body as `{"data": body, "meta": meta}` when
+/// `ENVELOPE` is enabled, leaving it as is otherwise.
+pub fn envelope(config: &Config, data: Value, meta: Value) -> Value {
+    if !config.envelope {
+        return data;
+    }
+
+    json!({ "data": data, "meta": meta })
+}
+
+pub fn format_rfc3339(time: SystemTime) -> String {
+    humantime::format_rfc3339_seconds(time).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{header::ACCEPT_LANGUAGE, HeaderMap, HeaderValue, StatusCode};
+    use serde_json::json;
+
+    use crate::testing;
+
+    use super::Locale;
+
+    fn negotiate(accept_language: &str) -> Locale {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT_LANGUAGE, accept_language.parse().unwrap());
+        Locale::negotiate(&headers)
+    }
+
+    #[test]
+    fn negotiates_locales() {
+        assert!(matches!(negotiate("fr-FR;q=0.9, en;q=0.8"), Locale::Fr));
+        assert!(matches!(negotiate("en;q=0.5, fr-CA"), Locale::Fr));
+        assert!(matches!(
+            negotiate("de-DE, fr;q=0.1, en-GB;q=0.7"),
+            Locale::En
+        ));
+        assert!(matches!(negotiate("de-DE, es;q=0.9"), Locale::En));
+        assert!(matches!(negotiate("FR"), Locale::Fr));
+        assert!(matches!(Locale::negotiate(&HeaderMap::new()), Locale::En));
+    }
+
+    #[test]
+    fn formats_cents_per_locale() {
+        let cases = [
+            (2990, "29.90", "29,90"),
+            (5, "0.05", "0,05"),
+            (-1234, "-12.34", "-12,34"),
+            (99_999, "999.99", "999,99"),
+            (100_000, "1,000.00", "1\u{202f}000,00"),
+            (-123_456_789, "-1,234,567.89", "-1\u{202f}234\u{202f}567,89"),
+        ];
+
+        for (cents, en, fr) in cases {
+            assert_eq!(Locale::En.format_cents(cents), en);
+            assert_eq!(Locale::Fr.format_cents(cents), fr);
+        }
+
+        let min = Locale::En.format_cents(i64::MIN);
+        assert_eq!(min, "-92,233,720,368,547,758.08");
+    }
+
+    #[tokio::test]
+    async fn cases_keys() {
+        let state = testing::state([("MOCK_MODE", "true")]);
+        let basket = json!({ "EPSON-SCP9500": 1 });
+
+        let req = testing::post("/prices?include=fetched_at", basket.clone());
+        let res = testing::send(&state, req).await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.body["monthly"]["connect"], 2990);
+        assert!(res.body["fetched_at"].is_string());
+
+        let req = testing::post("/prices?include=fetched_at&case=pascal", basket);
+        let res = testing::send(&state, req).await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.body["Monthly"]["Connect"], 2990);
+        assert_eq!(res.body["Tiers"]["Production"]["90"], 24900);
+        assert!(res.body["FetchedAt"].is_string());
+        assert!(res.body.get("monthly").is_none());
+    }
+
+    #[tokio::test]
+    async fn envelopes_successful_bodies() {
+        let basket = json!({ "EPSON-SCP9500": 1 });
+
+        let state = testing::state([("MOCK_MODE", "true")]);
+        let res = testing::send(&state, testing::post("/prices", basket.clone())).await;
+        assert_eq!(res.body["monthly"]["connect"], 2990);
+        assert!(res.body.get("data").is_none());
+        let res = testing::send(&state, testing::get("/drivers")).await;
+        assert_eq!(res.body.as_array().map(Vec::len), Some(4));
+
+        let state = testing::state([("MOCK_MODE", "true"), ("ENVELOPE", "true")]);
+        let mut req = testing::post("/prices", basket);
+        req.headers_mut()
+            .insert("x-request-id", HeaderValue::from_static("abc123"));
+        let res = testing::send(&state, req).await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.body["data"]["monthly"]["connect"], 2990);
+        assert_eq!(res.body["meta"]["cache"], "MISS");
+        assert_eq!(res.body["meta"]["request_id"], "abc123");
+
+        let res = testing::send(&state, testing::get("/drivers")).await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.body["data"].as_array().map(Vec::len), Some(4));
+        assert!(res.body["meta"]["etag"].is_string());
+
+        // Errors keep their own shape.
+        let res = testing::send(&state, testing::post("/prices", json!({}))).await;
+        assert_eq!(res.status, StatusCode::BAD_REQUEST);
+        assert!(res.body.get("data").is_none());
+        assert!(res.body["error"].is_string(), "{}", res.body);
+    }
+
+    #[tokio::test]
+    async fn selects_fields() {
+        let state = testing::state([("MOCK_MODE", "true")]);
+        let basket = json!({ "EPSON-SCP9500": 1 });
+
+        let req = testing::post("/prices?fields=yearly,monthly&fields=nope", basket.clone());
+        let res = testing::send(&state, req).await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(
+            res.body,
+            json!({
+                "yearly": { "connect": 2492, "production": 7492 },
+                "monthly": { "connect": 2990, "production": 8990 },
+            })
+        );
+
+        let res = testing::send(&state, testing::get("/drivers?fields=code")).await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.body[0], json!({ "code": "EPSON-SCP9500" }));
+        assert_eq!(res.body.as_array().map(Vec::len), Some(4));
+
+        let state = testing::state([("MOCK_MODE", "true"), ("UNKNOWN_FIELDS", "reject")]);
+        let req = testing::post("/prices?fields=yearly,nope", basket);
+        let res = testing::send(&state, req).await;
+        assert_eq!(res.status, StatusCode::BAD_REQUEST);
+        assert_eq!(res.body["details"][0]["field"], "fields.nope");
+
+        let res = testing::send(&state, testing::get("/drivers?fields=code,price")).await;
+        assert_eq!(res.status, StatusCode::BAD_REQUEST);
+        assert_eq!(res.body["details"][0]["field"], "fields.price");
+
+        let res = testing::send(&state, testing::get("/drivers?fields=code")).await;
+        assert_eq!(res.status, StatusCode::OK);
+    }
+}
@@ -0,0 +1,77 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use serde::Serialize;
+use tokio::time;
+use tracing::{debug, warn};
+
+use crate::{cache, output, prices, state::AppState};
+
+#[derive(Clone, Serialize)]
+pub struct PrefetchStatus {
+    products: Vec<(String, usize)>,
+    currency: String,
+    /// Absent when the basket could not be priced.
+    basket: Option<String>,
+    prefetched_at: String,
+    error: Option<String>,
+}
+
+/// Outcome of the last prefetch of each hot basket, in `HOT_BASKETS`
+/// order.
+#[derive(Default)]
+pub struct Prefetches(Mutex<Vec<PrefetchStatus>>);
+
+impl Prefetches {
+    pub fn statuses(&self) -> Vec<PrefetchStatus> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// Prices the `HOT_BASKETS` every `HOT_BASKETS_INTERVAL`, so that they
+/// are always served from the cache. Runs even without hot baskets, so
+/// that some can be added on reload.
+pub fn spawn_prefetcher(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        loop {
+            let config = state.config();
+            let mut statuses = Vec::with_capacity(config.hot_baskets.len());
+
+            for basket in &config.hot_baskets {
+                let products: Vec<(String, usize)> = basket
+                    .products
+                    .iter()
+                    .map(|(code, qty)| (code.clone(), *qty))
+                    .collect();
+
+                let res = prices::prefetch_prices(&state, products.clone(), &basket.currency).await;
+
+                let (key, error) = match res {
+                    Ok(key) => {
+                        debug!("prefetched prices of hot basket {key}");
+                        (Some(key), None)
+                    }
+                    Err(err) => {
+                        warn!("cannot prefetch prices of hot basket: {err}");
+                        (None, Some(err.to_string()))
+                    }
+                };
+
+                statuses.push(PrefetchStatus {
+                    products,
+                    currency: basket.currency.clone(),
+                    basket: key,
+                    prefetched_at: output::format_rfc3339(SystemTime::now()),
+                    error,
+                });
+            }
+
+            *state.prefetches.0.lock().unwrap() = statuses;
+
+            let interval = config.hot_baskets_interval;
+            time::sleep(cache::jitter(interval, config.cache_jitter)).await;
+        }
+    });
+}
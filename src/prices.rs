@@ -0,0 +1,1341 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::{self, Display},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use async_graphql::SimpleObject;
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{header::CONTENT_TYPE, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use rust_decimal::{
+    prelude::{FromPrimitive, ToPrimitive},
+    Decimal, RoundingStrategy,
+};
+use serde::{
+    de::{self, DeserializeOwned},
+    Deserialize, Deserializer, Serialize,
+};
+use serde_json::{json, value::RawValue, Value};
+use tokio::{sync::OnceCell, task::JoinSet};
+use tracing::{debug, error, warn};
+
+use crate::{
+    cache::{self, Freshness, Lookup},
+    config::{Config, PricesRequestFormat},
+    drivers,
+    error::Error,
+    output::{self, Output},
+    query::{self, Query},
+    state::AppState,
+    upstream::{self, Context},
+    validation, webhook,
+};
+
+static MOCK_PRICES: &str = include_str!("../fixtures/prices.json");
+
+pub static DEFAULT_CURRENCY: &str = "EUR";
+
+/// Country and dealer sent with every prices request, which upstream
+/// prices for when set. Part of the cache key, see
+/// [`cache::basket_key`].
+pub static COUNTRY: &str = "";
+pub static DEALER: &str = "";
+
+static X_BASKET_HASH: &str = "x-basket-hash";
+
+/// Fields that can be selected with `fields`.
+static FIELDS: [&str; 9] = [
+    "yearly",
+    "monthly",
+    "missing",
+    "basket",
+    "currency",
+    "tiers",
+    "markup",
+    "type",
+    "fetched_at",
+];
+
+static TIERS: [&str; 4] = [
+    "yearly.connect",
+    "yearly.production",
+    "monthly.connect",
+    "monthly.production",
+];
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+enum Plan {
+    Connect,
+    Production,
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct GetPricesResponse<P> {
+    r#type: String,
+    results: Vec<(Plan, i16, P, P)>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Products(pub HashMap<String, usize>);
+
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize, SimpleObject)]
+pub struct Prices {
+    yearly: PlanPrice,
+    monthly: PlanPrice,
+    /// Tiers upstream did not return a price for, as `period.plan`.
+    missing: Vec<String>,
+    /// Canonical hash of the basket, also used as the cache key.
+    basket: String,
+    /// Currency the prices are denominated in, as sent upstream.
+    currency: String,
+    tiers: Tiers,
+    /// Factors applied to upstream prices, 1 when left unchanged.
+    markup: Markup,
+    /// Pricing type reported by upstream, only serialized in REST
+    /// responses when requested with `include=type`.
+    r#type: String,
+}
+
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize, SimpleObject)]
+struct PlanPrice {
+    connect: i64,
+    production: i64,
+}
+
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize, SimpleObject)]
+struct Markup {
+    connect: f64,
+    production: f64,
+}
+
+/// Every price returned by upstream per plan, in cents, keyed by
+/// duration in days (or volume tier for some products).
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize, SimpleObject)]
+struct Tiers {
+    connect: BTreeMap<i16, i64>,
+    production: BTreeMap<i16, i64>,
+}
+
+impl Prices {
+    fn new(config: &Config) -> Self {
+        Self {
+            missing: TIERS.map(String::from).to_vec(),
+            markup: Markup {
+                connect: config.markup_connect,
+                production: config.markup_production,
+            },
+            ..Default::default()
+        }
+    }
+
+    fn found(&mut self, tier: &str) {
+        self.missing.retain(|missing| missing != tier);
+    }
+
+    /// Returns the first cents value outside of `0..=max`, if any.
+    fn find_out_of_range(&self, max: i64) -> Option<i64> {
+        [
+            self.yearly.connect,
+            self.yearly.production,
+            self.monthly.connect,
+            self.monthly.production,
+        ]
+        .into_iter()
+        .chain(self.tiers.connect.values().copied())
+        .chain(self.tiers.production.values().copied())
+        .find(|cents| !(0..=max).contains(cents))
+    }
+
+    /// Raises the prices below the floor of their plan to it, see
+    /// `PRICE_FLOOR_CONNECT` and `PRICE_FLOOR_PRODUCTION`. Missing
+    /// prices are left as is.
+    fn apply_floors(&mut self, config: &Config) {
+        let plans = [
+            (
+                "connect",
+                config.price_floor_connect,
+                &mut self.yearly.connect,
+                &mut self.monthly.connect,
+                &mut self.tiers.connect,
+            ),
+            (
+                "production",
+                config.price_floor_production,
+                &mut self.yearly.production,
+                &mut self.monthly.production,
+                &mut self.tiers.production,
+            ),
+        ];
+
+        for (plan, floor, yearly, monthly, tiers) in plans {
+            for (period, cents) in [("yearly", yearly), ("monthly", monthly)] {
+                let tier = format!("{period}.{plan}");
+                if !self.missing.contains(&tier) {
+                    clamp_to_floor(&tier, cents, floor);
+                }
+            }
+
+            for (a, cents) in tiers {
+                clamp_to_floor(&format!("{plan} tier {a}"), cents, floor);
+            }
+        }
+    }
+}
+
+fn clamp_to_floor(label: &str, cents: &mut i64, floor: i64) {
+    if *cents < floor {
+        warn!("raising {label} price from {cents} to the {floor} cents floor");
+        *cents = floor;
+    }
+}
+
+impl Prices {
+    /// Serializes the prices as shaped by the output options, except for
+    /// the keys case which applies to the whole response.
+    pub fn to_body(
+        &self,
+        freshness: &Freshness,
+        config: &Config,
+        output: &Output,
+    ) -> Result<Value, Error> {
+        let mut body = serde_json::to_value(self)?;
+        output.format_prices(&mut body, &["yearly", "monthly", "tiers"]);
+        alias_plans(&mut body, &config.plan_aliases);
+
+        if output.includes("fetched_at") {
+            body["fetched_at"] = output::format_rfc3339(freshness.fetched_at()).into();
+        }
+
+        if let Some(body) = body.as_object_mut() {
+            if !output.includes("type") {
+                body.remove("type");
+            }
+
+            // Unsummarized prices only keep the tiers map, as in
+            // `{"tiers": {"connect": {"30": 2990, "365": 29900}, …}, …}`.
+            if !output.summarize {
+                for key in ["yearly", "monthly", "missing"] {
+                    body.remove(key);
+                }
+            }
+        }
+
+        Ok(body)
+    }
+
+    fn render(
+        self,
+        freshness: Freshness,
+        config: &Config,
+        output: &Output,
+        ctx: &Context,
+    ) -> Result<Response, Error> {
+        let mut body = self.to_body(&freshness, config, output)?;
+        output.select_fields(&mut body);
+        let meta = json!({
+            "cache": freshness.status.as_str(),
+            "age": freshness.age.as_secs(),
+            "request_id": ctx.request_id(),
+        });
+        let mut body = output::envelope(config, body, meta);
+        output.apply_case(&mut body);
+
+        let headers = freshness.headers();
+        Ok((headers, [(X_BASKET_HASH, self.basket)], Json(body)).into_response())
+    }
+}
+
+/// Renames plans in the serialized prices according to `PLAN_ALIASES`,
+/// in both the period objects keys and the missing tiers labels.
+fn alias_plans(body: &mut Value, aliases: &HashMap<String, String>) {
+    if aliases.is_empty() {
+        return;
+    }
+
+    for period in ["yearly", "monthly", "tiers", "markup"] {
+        if let Some(Value::Object(plans)) = body.get_mut(period) {
+            *plans = std::mem::take(plans)
+                .into_iter()
+                .map(|(plan, price)| match aliases.get(&plan) {
+                    Some(alias) => (alias.clone(), price),
+                    None => (plan, price),
+                })
+                .collect();
+        }
+    }
+
+    if let Some(Value::Array(tiers)) = body.get_mut("missing") {
+        for tier in tiers {
+            let Some((period, plan)) = tier.as_str().and_then(|tier| tier.split_once('.')) else {
+                continue;
+            };
+
+            if let Some(alias) = aliases.get(plan) {
+                *tier = Value::String(format!("{period}.{alias}"));
+            }
+        }
+    }
+}
+
+/// Runs every check a basket goes through before being priced. Codes
+/// are checked against the cached drivers only, never fetched from
+/// here, so that pricing does not wait on the drivers.
+fn validate(state: &AppState, products: &[(String, usize)], currency: &str) -> Result<(), Error> {
+    let drivers = state.drivers.read().unwrap().clone();
+    let config = state.config();
+    validation::validate_basket(&config, drivers.as_deref(), products, currency)?;
+    validation::check_allowed_products(&config, products)?;
+    Ok(())
+}
+
+/// Prices the given products, from the cache when possible. Expired
+/// prices are served stale while being refreshed in the background.
+pub async fn fetch_prices(
+    state: &Arc<AppState>,
+    ctx: &Context,
+    products: Vec<(String, usize)>,
+    currency: &str,
+) -> Result<(Prices, Freshness), Error> {
+    validate(state, &products, currency)?;
+    let key = cache::basket_key(&products, currency);
+
+    match state.prices.get(&key, &state.config()) {
+        Lookup::Hit(prices, freshness) => {
+            debug!("prices cache hit for basket {key}");
+            return Ok((prices, freshness));
+        }
+        Lookup::Stale {
+            prices,
+            freshness,
+            refresh,
+        } => {
+            debug!("serving stale prices for basket {key}");
+            if refresh {
+                spawn_prices_refresh(state.clone(), key, products, currency.to_owned());
+            }
+            return Ok((prices, freshness));
+        }
+        Lookup::Miss => {}
+    }
+
+    let (prices, ttl) = fetch_and_cache_prices(state, ctx, key, products, currency).await?;
+    Ok((prices, Freshness::miss(ttl)))
+}
+
+/// Prices the given basket from upstream and caches it, whether it was
+/// already cached or not. Returns the basket key.
+pub async fn prefetch_prices(
+    state: &AppState,
+    products: Vec<(String, usize)>,
+    currency: &str,
+) -> Result<String, Error> {
+    validate(state, &products, currency)?;
+    let key = cache::basket_key(&products, currency);
+    let ctx = Context::default();
+    fetch_and_cache_prices(state, &ctx, key.clone(), products, currency).await?;
+    Ok(key)
+}
+
+type Fetched = Result<(Prices, Duration), Error>;
+
+/// Prices fetches in progress by basket key, see
+/// [`fetch_and_cache_prices`].
+#[derive(Default)]
+pub struct PendingFetches(Mutex<HashMap<String, Arc<OnceCell<Fetched>>>>);
+
+/// Same as [`fetch_and_cache_uncoalesced`], except that concurrent
+/// fetches of the same basket share a single upstream call, made with
+/// the context of the first one.
+async fn fetch_and_cache_prices(
+    state: &AppState,
+    ctx: &Context,
+    key: String,
+    products: Vec<(String, usize)>,
+    currency: &str,
+) -> Fetched {
+    let fetch = state
+        .pending_fetches
+        .0
+        .lock()
+        .unwrap()
+        .entry(key.clone())
+        .or_default()
+        .clone();
+
+    let res = fetch
+        .get_or_init(|| fetch_and_cache_uncoalesced(state, ctx, key.clone(), products, currency))
+        .await
+        .clone();
+
+    // Forgotten once done, so that the next fetch calls upstream again.
+    let mut pending = state.pending_fetches.0.lock().unwrap();
+    if pending.get(&key).is_some_and(|f| Arc::ptr_eq(f, &fetch)) {
+        pending.remove(&key);
+    }
+
+    res
+}
+
+async fn fetch_and_cache_uncoalesced(
+    state: &AppState,
+    ctx: &Context,
+    key: String,
+    products: Vec<(String, usize)>,
+    currency: &str,
+) -> Fetched {
+    // Taken before anything is read from the configuration.
+    let generation = state.prices.generation();
+    let mut prices = fetch_upstream_prices(state, ctx, products, currency).await?;
+    prices.basket = key.clone();
+    prices.currency = currency.to_owned();
+    let ttl = cache::jitter(state.config().prices_cache_ttl, state.config().cache_jitter);
+
+    if let Some(previous) = state.prices.insert(key, prices.clone(), ttl, generation) {
+        if previous != prices {
+            webhook::notify_price_change(state, previous, prices.clone());
+        }
+    }
+
+    Ok((prices, ttl))
+}
+
+fn spawn_prices_refresh(
+    state: Arc<AppState>,
+    key: String,
+    products: Vec<(String, usize)>,
+    currency: String,
+) {
+    tokio::spawn(async move {
+        let ctx = Context::default();
+        let res = fetch_and_cache_prices(&state, &ctx, key.clone(), products, &currency).await;
+        if let Err(err) = res {
+            warn!("cannot refresh prices of basket {key}: {err}");
+            state.prices.release(&key);
+        }
+    });
+}
+
+/// Body of the JSON upstream prices request. Serialized straight from
+/// the products, which large baskets would otherwise copy into an
+/// intermediate JSON value.
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct UpstreamPricesRequest<'a> {
+    product: &'static str,
+    currency: &'a str,
+    products: &'a [(String, usize)],
+    country: &'static str,
+    dealer: Option<&'static str>,
+}
+
+async fn fetch_upstream_payload(
+    state: &AppState,
+    ctx: &Context,
+    products: Vec<(String, usize)>,
+    currency: &str,
+) -> Result<Bytes, Error> {
+    if state.config().mock_mode {
+        return Ok(Bytes::from_static(MOCK_PRICES.as_bytes()));
+    }
+
+    let req = state.client.post(state.config().prices_url.clone());
+    let req = match state.config().prices_request_format {
+        PricesRequestFormat::Json => req.body(serde_json::to_vec(&UpstreamPricesRequest {
+            product: "PrintFactory",
+            currency,
+            products: &products,
+            country: COUNTRY,
+            dealer: Some(DEALER).filter(|dealer| !dealer.is_empty()),
+        })?),
+        // Products cannot be flattened into form fields, they are sent
+        // as JSON as well.
+        PricesRequestFormat::Form => req.form(&[
+            ("Product", "PrintFactory"),
+            ("Currency", currency),
+            ("Products", &serde_json::to_string(&products)?),
+            ("Country", COUNTRY),
+            ("Dealer", DEALER),
+        ]),
+    };
+    let res = upstream::send(state, ctx, req).await?;
+
+    // Error bodies are neither prices nor worth passing through raw.
+    if !res.status().is_success() {
+        return Err(Error::new(
+            StatusCode::BAD_GATEWAY,
+            anyhow!("upstream answered {}", res.status()),
+        ));
+    }
+
+    Ok(res.bytes().await?)
+}
+
+/// A price as parsed from the upstream payload.
+trait UpstreamPrice: DeserializeOwned + Display + Copy {
+    /// Whether the price is finite and positive.
+    fn is_valid(self) -> bool;
+
+    /// Applies the markup, then returns the price and its monthly share
+    /// when billed yearly, both rounded to cents.
+    fn to_cents(self, markup: f64) -> (i64, i64);
+}
+
+impl UpstreamPrice for f32 {
+    fn is_valid(self) -> bool {
+        self.is_finite() && self >= 0.0
+    }
+
+    fn to_cents(self, markup: f64) -> (i64, i64) {
+        // Widened rather than narrowing the markup, which would lose
+        // precision before rounding.
+        let c = self as f64 * markup;
+        (
+            (c * 100.0).round() as i64,
+            ((c / 12.0) * 100.0).round() as i64,
+        )
+    }
+}
+
+/// Upstream price parsed in decimal from the number as written in the
+/// payload, rather than through a float which would already have lost
+/// precision. Quoted prices are accepted as well.
+#[derive(Clone, Copy)]
+struct DecimalPrice(Decimal);
+
+impl<'de> Deserialize<'de> for DecimalPrice {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = Box::<RawValue>::deserialize(deserializer)?;
+        let raw = raw.get();
+        let price = raw
+            .strip_prefix('"')
+            .and_then(|price| price.strip_suffix('"'))
+            .unwrap_or(raw);
+
+        Decimal::from_str(price)
+            .or_else(|_| Decimal::from_scientific(price))
+            .map(Self)
+            .map_err(|err| de::Error::custom(format!("invalid price {raw}: {err}")))
+    }
+}
+
+impl Display for DecimalPrice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl UpstreamPrice for DecimalPrice {
+    fn is_valid(self) -> bool {
+        !self.0.is_sign_negative()
+    }
+
+    fn to_cents(self, markup: f64) -> (i64, i64) {
+        // Rounds half away from zero like floats do, and saturates so
+        // that overflowing prices are caught by the range check.
+        let cents = |c: Option<Decimal>| {
+            c.and_then(|c| c.checked_mul(Decimal::ONE_HUNDRED))
+                .and_then(|c| {
+                    c.round_dp_with_strategy(0, RoundingStrategy::MidpointAwayFromZero)
+                        .to_i64()
+                })
+                .unwrap_or(i64::MAX)
+        };
+        let markup = Decimal::from_f64(markup).unwrap_or(Decimal::ONE);
+        let c = self.0.checked_mul(markup);
+        let yearly = c.and_then(|c| c.checked_div(Decimal::from(12)));
+        (cents(c), cents(yearly))
+    }
+}
+
+fn fold_upstream_prices<P: UpstreamPrice>(config: &Config, bytes: &Bytes) -> Result<Prices, Error> {
+    let res: GetPricesResponse<P> = match serde_json::from_slice(bytes) {
+        Ok(res) => res,
+        Err(err) => {
+            let payload = String::from_utf8_lossy(bytes);
+            error!("cannot parse upstream prices payload: {err}: {payload}");
+            let err = anyhow!("upstream returned invalid prices: {err}");
+            return Err(Error::new(StatusCode::BAD_GATEWAY, err));
+        }
+    };
+
+    // Casting a NaN or an infinite float to an integer silently yields
+    // nonsense, so such prices are rejected before the fold.
+    let invalid = res
+        .results
+        .iter()
+        .find(|(plan, _, _, c)| !matches!(plan, Plan::Other) && !c.is_valid());
+
+    if let Some((_, a, _, c)) = invalid {
+        let payload = String::from_utf8_lossy(bytes);
+        error!("invalid price {c} for tier {a} in upstream payload: {payload}");
+        return Err(Error::new(
+            StatusCode::BAD_GATEWAY,
+            anyhow!("upstream returned an invalid price for tier {a}"),
+        ));
+    }
+
+    let mut prices =
+        res.results
+            .into_iter()
+            .fold(Prices::new(config), |mut prices, (plan, a, _b, c)| {
+                // Rounding happens after the markup, so that it does not
+                // amplify rounding errors.
+                let (cents, yearly_cents) = match plan {
+                    Plan::Connect => c.to_cents(prices.markup.connect),
+                    Plan::Production => c.to_cents(prices.markup.production),
+                    Plan::Other => c.to_cents(1.0),
+                };
+
+                match plan {
+                    Plan::Connect => prices.tiers.connect.insert(a, cents),
+                    Plan::Production => prices.tiers.production.insert(a, cents),
+                    Plan::Other => None,
+                };
+
+                match plan {
+                    Plan::Connect if a == 30 => {
+                        prices.monthly.connect = cents;
+                        prices.found("monthly.connect");
+                    }
+                    Plan::Connect if a == 365 => {
+                        prices.yearly.connect = yearly_cents;
+                        prices.found("yearly.connect");
+                    }
+                    Plan::Production if a == 30 => {
+                        prices.monthly.production = cents;
+                        prices.found("monthly.production");
+                    }
+                    Plan::Production if a == 365 => {
+                        prices.yearly.production = yearly_cents;
+                        prices.found("yearly.production");
+                    }
+                    Plan::Production => {}
+                    _ => {}
+                };
+                prices
+            });
+    prices.r#type = res.r#type;
+    Ok(prices)
+}
+
+pub async fn fetch_upstream_prices(
+    state: &AppState,
+    ctx: &Context,
+    products: Vec<(String, usize)>,
+    currency: &str,
+) -> Result<Prices, Error> {
+    let bytes = fetch_upstream_payload(state, ctx, products, currency).await?;
+    let mut prices = if state.config().decimal_pricing {
+        fold_upstream_prices::<DecimalPrice>(&state.config(), &bytes)?
+    } else {
+        fold_upstream_prices::<f32>(&state.config(), &bytes)?
+    };
+
+    if let Some(cents) = prices.find_out_of_range(state.config().prices_max_cents) {
+        let payload = String::from_utf8_lossy(&bytes);
+        error!("invalid price {cents} computed from upstream payload: {payload}");
+        return Err(Error::new(
+            StatusCode::BAD_GATEWAY,
+            anyhow!("upstream returned an invalid price"),
+        ));
+    }
+
+    prices.apply_floors(&state.config());
+    Ok(prices)
+}
+
+pub async fn get_prices(
+    State(state): State<Arc<AppState>>,
+    ctx: Context,
+    output: Output,
+    Json(products): Json<Products>,
+) -> Result<Response, Error> {
+    output.check_fields(&state.config(), &FIELDS)?;
+    let products = products.0.into_iter().collect();
+    let (prices, freshness) = fetch_prices(&state, &ctx, products, DEFAULT_CURRENCY).await?;
+    prices.render(freshness, &state.config(), &output, &ctx)
+}
+
+/// Returns the upstream prices payload as is, without folding it into
+/// [`Prices`] nor caching it.
+pub async fn get_raw_prices(
+    State(state): State<Arc<AppState>>,
+    ctx: Context,
+    Json(products): Json<Products>,
+) -> Result<Response, Error> {
+    let products: Vec<_> = products.0.into_iter().collect();
+    validate(&state, &products, DEFAULT_CURRENCY)?;
+    let bytes = fetch_upstream_payload(&state, &ctx, products, DEFAULT_CURRENCY).await?;
+    Ok(([(CONTENT_TYPE, "application/json")], bytes).into_response())
+}
+
+#[derive(Deserialize)]
+pub struct ProductQuery {
+    #[serde(default, deserialize_with = "query::last")]
+    qty: Option<usize>,
+    #[serde(default, deserialize_with = "query::last")]
+    currency: Option<String>,
+}
+
+pub async fn get_product_prices(
+    State(state): State<Arc<AppState>>,
+    Path(code): Path<String>,
+    Query(query): Query<ProductQuery>,
+    ctx: Context,
+    output: Output,
+) -> Result<Response, Error> {
+    output.check_fields(&state.config(), &FIELDS)?;
+    let qty = query.qty.unwrap_or(state.config().default_quantity);
+    let products = vec![(code, qty)];
+    let currency = query.currency.as_deref().unwrap_or(DEFAULT_CURRENCY);
+    let (prices, freshness) = fetch_prices(&state, &ctx, products, currency).await?;
+    prices.render(freshness, &state.config(), &output, &ctx)
+}
+
+#[derive(Deserialize)]
+pub struct CompareRequest {
+    products: Products,
+    currencies: Vec<String>,
+}
+
+/// Prices of a basket in one currency, flattened for tables.
+#[derive(Serialize)]
+pub struct CompareRow {
+    currency: String,
+    basket: String,
+    monthly_connect: i64,
+    monthly_production: i64,
+    yearly_connect: i64,
+    yearly_production: i64,
+    missing: Vec<String>,
+}
+
+impl From<Prices> for CompareRow {
+    fn from(prices: Prices) -> Self {
+        Self {
+            currency: prices.currency,
+            basket: prices.basket,
+            monthly_connect: prices.monthly.connect,
+            monthly_production: prices.monthly.production,
+            yearly_connect: prices.yearly.connect,
+            yearly_production: prices.yearly.production,
+            missing: prices.missing,
+        }
+    }
+}
+
+/// Prices the same basket in several currencies, concurrently. Repeated
+/// currencies are priced once.
+pub async fn compare_prices(
+    State(state): State<Arc<AppState>>,
+    ctx: Context,
+    Json(req): Json<CompareRequest>,
+) -> Result<Json<Vec<CompareRow>>, Error> {
+    validation::validate_currencies(&req.currencies)?;
+
+    let mut currencies = req.currencies;
+    let mut seen = HashSet::new();
+    currencies.retain(|currency| seen.insert(currency.clone()));
+
+    let products: Vec<(String, usize)> = req.products.0.into_iter().collect();
+    let mut tasks = JoinSet::new();
+
+    for (i, currency) in currencies.into_iter().enumerate() {
+        let state = state.clone();
+        let ctx = ctx.clone();
+        let products = products.clone();
+        tasks.spawn(async move {
+            let res = fetch_prices(&state, &ctx, products, &currency).await;
+            (i, res)
+        });
+    }
+
+    let mut rows = Vec::with_capacity(tasks.len());
+    while let Some(res) = tasks.join_next().await {
+        let (i, res) = res?;
+        let (prices, _) = res?;
+        rows.push((i, CompareRow::from(prices)));
+    }
+
+    rows.sort_by_key(|(i, _)| *i);
+    Ok(Json(rows.into_iter().map(|(_, row)| row).collect()))
+}
+
+#[derive(Deserialize)]
+pub struct ValidateQuery {
+    #[serde(default, deserialize_with = "query::last")]
+    currency: Option<String>,
+}
+
+/// Validates a basket the same way pricing it would, without calling
+/// upstream for prices.
+pub async fn validate_prices(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ValidateQuery>,
+    Json(products): Json<Products>,
+) -> Result<Json<Value>, Error> {
+    let products: Vec<(String, usize)> = products.0.into_iter().collect();
+    let currency = query.currency.as_deref().unwrap_or(DEFAULT_CURRENCY);
+    // Unlike pricing, validating is worth waiting for the drivers, so
+    // that codes are always checked.
+    drivers::cached_drivers(&state).await?;
+    validate(&state, &products, currency)?;
+    Ok(Json(json!({ "valid": true })))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{BTreeMap, HashMap},
+        io::Write,
+        time::Duration,
+    };
+
+    use axum::{
+        body::Bytes,
+        http::{Request, StatusCode},
+        response::IntoResponse,
+        routing, Json,
+    };
+    use flate2::{write::GzEncoder, Compression};
+    use serde_json::{json, Value};
+    use tokio::time;
+
+    use crate::{
+        config::Config,
+        drivers,
+        error::Error,
+        testing::{self, Logs, Upstream},
+    };
+
+    use super::{alias_plans, fold_upstream_prices, DecimalPrice, Prices, UpstreamPrice};
+
+    /// Returns the products sent in each upstream prices request.
+    fn upstream_products(upstream: &Upstream) -> Vec<Value> {
+        upstream
+            .requests_to("/_prices.asp")
+            .iter()
+            .map(|req| serde_json::from_slice::<Value>(&req.body).unwrap()["Products"].clone())
+            .collect()
+    }
+
+    fn fold(config: &Config, results: Value) -> Result<Prices, Error> {
+        fold_as::<f32>(config, results)
+    }
+
+    fn fold_as<P: UpstreamPrice>(config: &Config, results: Value) -> Result<Prices, Error> {
+        let payload = json!({ "Type": "Subscription", "Results": results });
+        fold_upstream_prices::<P>(config, &Bytes::from(payload.to_string()))
+    }
+
+    #[test]
+    fn folds_upstream_prices() {
+        let config = testing::config([]);
+        let results = json!([
+            ["Connect", 30, 1, 29.9],
+            ["Connect", 365, 1, 299.0],
+            ["Production", 30, 1, 89.9],
+            ["Production", 90, 1, 249.0],
+        ]);
+        let prices = fold(&config, results).unwrap();
+
+        assert_eq!(prices.monthly.connect, 2990);
+        assert_eq!(prices.yearly.connect, 2492);
+        assert_eq!(prices.monthly.production, 8990);
+        assert_eq!(prices.missing, ["yearly.production"]);
+        assert_eq!(prices.tiers.connect.get(&365), Some(&29900));
+        assert_eq!(prices.tiers.production.get(&90), Some(&24900));
+        assert_eq!(prices.r#type, "Subscription");
+        assert_eq!(prices.find_out_of_range(config.prices_max_cents), None);
+    }
+
+    #[test]
+    fn finds_out_of_range_prices() {
+        let config = testing::config([]);
+        let results = json!([["Connect", 30, 1, 29.9], ["Production", 90, 1, 20000.0],]);
+        let prices = fold(&config, results).unwrap();
+
+        assert_eq!(prices.find_out_of_range(1_000_000), Some(2_000_000));
+        assert_eq!(prices.find_out_of_range(2_000_000), None);
+    }
+
+    #[tokio::test]
+    async fn prices_a_single_product() {
+        let upstream = Upstream::start(testing::fixtures()).await;
+        let state = upstream.state([]);
+
+        let res = testing::send(&state, testing::get("/prices/EPSON-SCP9500")).await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.body["monthly"]["connect"], 2990);
+
+        let req = testing::get("/prices/EPSON-SCP9500?qty=3&currency=USD");
+        let res = testing::send(&state, req).await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.body["currency"], "USD");
+
+        let products = upstream_products(&upstream);
+        assert_eq!(
+            products,
+            [json!([["EPSON-SCP9500", 1]]), json!([["EPSON-SCP9500", 3]])]
+        );
+    }
+
+    #[tokio::test]
+    async fn lists_missing_tiers() {
+        let partial = json!({ "Type": "Subscription", "Results": [["Connect", 30, 1, 29.9]] });
+        let routes = testing::drivers().route(
+            "/_prices.asp",
+            routing::post(move || async move { Json(partial) }),
+        );
+        let upstream = Upstream::start(routes).await;
+        let state = upstream.state([]);
+
+        let req = testing::post("/prices", json!({ "EPSON-SCP9500": 1 }));
+        let res = testing::send(&state, req).await;
+
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.body["monthly"]["connect"], 2990);
+        assert_eq!(res.body["yearly"]["connect"], 0);
+        assert_eq!(
+            res.body["missing"],
+            json!(["yearly.connect", "yearly.production", "monthly.production"])
+        );
+    }
+
+    #[tokio::test]
+    async fn aliases_plans() {
+        let aliases = r#"{"connect": "basic", "production": "pro"}"#;
+        let state = testing::state([("MOCK_MODE", "true"), ("PLAN_ALIASES", aliases)]);
+
+        let basket = json!({ "EPSON-SCP9500": 1 });
+        let res = testing::send(&state, testing::post("/prices", basket)).await;
+
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.body["monthly"], json!({ "basic": 2990, "pro": 8990 }));
+        assert_eq!(res.body["yearly"], json!({ "basic": 2492, "pro": 7492 }));
+        assert_eq!(res.body["tiers"]["pro"]["90"], 24900);
+        assert_eq!(res.body["markup"], json!({ "basic": 1.0, "pro": 1.0 }));
+        assert_eq!(res.body["missing"], json!([]));
+        assert!(res.body["monthly"].get("connect").is_none());
+
+        let mut body = json!({ "missing": ["yearly.connect", "monthly.production"] });
+        alias_plans(&mut body, &state.config().plan_aliases);
+        assert_eq!(body["missing"], json!(["yearly.basic", "monthly.pro"]));
+    }
+
+    #[tokio::test]
+    async fn sends_form_payloads() {
+        let upstream = Upstream::start(testing::fixtures()).await;
+        let state = upstream.state([("PRICES_REQUEST_FORMAT", "form")]);
+
+        let res = testing::send(&state, testing::get("/prices/EPSON-SCP9500?qty=2")).await;
+        assert_eq!(res.status, StatusCode::OK);
+
+        let req = &upstream.requests_to("/_prices.asp")[0];
+        let content_type = &req.headers["content-type"];
+        assert_eq!(content_type, "application/x-www-form-urlencoded");
+
+        let form: HashMap<String, String> = serde_html_form::from_bytes(&req.body).unwrap();
+        assert_eq!(form["Product"], "PrintFactory");
+        assert_eq!(form["Currency"], "EUR");
+        assert_eq!(form["Products"], r#"[["EPSON-SCP9500",2]]"#);
+        assert_eq!(form["Country"], "");
+        assert_eq!(form["Dealer"], "");
+    }
+
+    #[test]
+    fn keeps_every_tier() {
+        let config = testing::config([]);
+        let results = json!([
+            ["Connect", 30, 1, 29.9],
+            ["Connect", 365, 1, 299.0],
+            ["Production", 30, 1, 89.9],
+            ["Production", 90, 1, 249.0],
+            ["Production", 180, 1, 479.0],
+            ["Production", 365, 1, 899.0],
+            ["Production", 1000, 1, 1500.0],
+            ["Other", 30, 1, 5.0],
+        ]);
+        let prices = fold(&config, results).unwrap();
+
+        let tiers = |tiers: &[(i16, i64)]| tiers.iter().copied().collect::<BTreeMap<_, _>>();
+        assert_eq!(prices.tiers.connect, tiers(&[(30, 2990), (365, 29900)]));
+        assert_eq!(
+            prices.tiers.production,
+            tiers(&[
+                (30, 8990),
+                (90, 24900),
+                (180, 47900),
+                (365, 89900),
+                (1000, 150000),
+            ])
+        );
+        assert_eq!(prices.monthly.production, 8990);
+        assert_eq!(prices.yearly.production, 7492);
+        assert!(prices.missing.is_empty());
+    }
+
+    #[tokio::test]
+    async fn echoes_the_currency() {
+        let upstream = Upstream::start(testing::fixtures()).await;
+        let state = upstream.state([]);
+
+        let req = testing::get("/prices/EPSON-SCP9500?currency=CHF");
+        let res = testing::send(&state, req).await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.body["currency"], "CHF");
+
+        let res = testing::send(&state, testing::get("/prices/EPSON-SCP9500")).await;
+        assert_eq!(res.body["currency"], "EUR");
+
+        let requests = upstream.requests_to("/_prices.asp");
+        let currency = |i: usize| {
+            let body: Value = serde_json::from_slice(&requests[i].body).unwrap();
+            body["Currency"].clone()
+        };
+        assert_eq!(currency(0), "CHF");
+        assert_eq!(currency(1), "EUR");
+    }
+
+    #[test]
+    fn applies_markups_before_rounding() {
+        let config = testing::config([("MARKUP_CONNECT", "1.1"), ("MARKUP_PRODUCTION", "1.15")]);
+        let results = json!([
+            ["Connect", 30, 1, 29.9],
+            ["Connect", 365, 1, 299.0],
+            ["Production", 30, 1, 89.9],
+            ["Production", 365, 1, 899.0],
+            ["Other", 30, 1, 10.0],
+        ]);
+        let prices = fold(&config, results).unwrap();
+
+        assert_eq!(prices.monthly.connect, 3289);
+        assert_eq!(prices.yearly.connect, 2741);
+        assert_eq!(prices.monthly.production, 10339);
+        assert_eq!(prices.yearly.production, 8615);
+        assert_eq!(prices.tiers.production.get(&365), Some(&103385));
+        assert_eq!(prices.markup.connect, 1.1);
+        assert_eq!(prices.markup.production, 1.15);
+    }
+
+    #[tokio::test]
+    async fn compares_currencies() {
+        let upstream = Upstream::start(testing::fixtures()).await;
+        let state = upstream.state([]);
+
+        let req =
+            json!({ "products": { "EPSON-SCP9500": 1 }, "currencies": ["EUR", "USD", "EUR"] });
+        let res = testing::send(&state, testing::post("/prices/compare", req)).await;
+        assert_eq!(res.status, StatusCode::OK);
+
+        let rows = res.body.as_array().unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["currency"], "EUR");
+        assert_eq!(rows[1]["currency"], "USD");
+        assert_eq!(rows[0]["monthly_connect"], 2990);
+        assert_eq!(rows[1]["yearly_production"], 7492);
+        assert_ne!(rows[0]["basket"], rows[1]["basket"]);
+        assert_eq!(upstream.requests_to("/_prices.asp").len(), 2);
+    }
+
+    #[tokio::test]
+    async fn coalesces_concurrent_fetches() {
+        let prices = routing::post(|| async {
+            time::sleep(Duration::from_millis(100)).await;
+            testing::PRICES
+        });
+        let upstream = Upstream::start(testing::drivers().route("/_prices.asp", prices)).await;
+        let state = upstream.state([]);
+        drivers::refresh_drivers(&state).await.unwrap();
+
+        let basket = json!({ "EPSON-SCP9500": 1 });
+        let (a, b) = tokio::join!(
+            testing::send(&state, testing::post("/prices", basket.clone())),
+            testing::send(&state, testing::post("/prices", basket)),
+        );
+
+        assert_eq!(a.status, StatusCode::OK);
+        assert_eq!(b.status, StatusCode::OK);
+        assert_eq!(a.body, b.body);
+        assert_eq!(upstream.requests_to("/_prices.asp").len(), 1);
+        assert!(state.pending_fetches.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_floats() {
+        let config = testing::config([]);
+        let invalid = [
+            (
+                json!(-5.0),
+                "upstream returned an invalid price for tier 30",
+            ),
+            // Overflows f32 into an infinite price.
+            (
+                json!(1e39),
+                "upstream returned an invalid price for tier 30",
+            ),
+            (json!("NaN"), "upstream returned invalid prices"),
+        ];
+
+        for (price, message) in invalid {
+            let Err(err) = fold(&config, json!([["Connect", 30, 1, price]])) else {
+                panic!("{price} should be rejected");
+            };
+            assert!(err.to_string().starts_with(message), "{err}");
+            assert_eq!(err.into_response().status(), StatusCode::BAD_GATEWAY);
+        }
+
+        // Prices of unknown plans are ignored, even invalid.
+        assert!(fold(&config, json!([["Other", 30, 1, -5.0]])).is_ok());
+    }
+
+    #[tokio::test]
+    async fn accepts_gzip_baskets() {
+        let state = testing::state([("MOCK_MODE", "true")]);
+        let basket = json!({ "EPSON-SCP9500": 1 }).to_string();
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(basket.as_bytes()).unwrap();
+        let req = Request::post("/prices")
+            .header("content-type", "application/json")
+            .header("content-encoding", "gzip")
+            .body(encoder.finish().unwrap().into())
+            .unwrap();
+        let res = testing::send(&state, req).await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.body["monthly"]["connect"], 2990);
+
+        let req = Request::post("/prices")
+            .header("content-type", "application/json")
+            .header("content-encoding", "br")
+            .body(basket.into())
+            .unwrap();
+        let res = testing::send(&state, req).await;
+        assert_eq!(res.status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[tokio::test]
+    async fn forwards_the_default_quantity() {
+        let upstream = Upstream::start(testing::fixtures()).await;
+        let state = upstream.state([("DEFAULT_QUANTITY", "5")]);
+
+        let res = testing::send(&state, testing::get("/prices/EPSON-SCP9500")).await;
+        assert_eq!(res.status, StatusCode::OK);
+
+        let query = r#"{ prices(products: [{ code: "HP-LATEX-800W" }]) { basket } }"#;
+        let res = testing::send(&state, testing::post("/graphql", json!({ "query": query }))).await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert!(
+            res.body["data"]["prices"]["basket"].is_string(),
+            "{}",
+            res.body
+        );
+
+        // An explicit 0 is still rejected rather than defaulted.
+        let res = testing::send(&state, testing::get("/prices/EPSON-SCP9500?qty=0")).await;
+        assert_eq!(res.status, StatusCode::BAD_REQUEST);
+
+        assert_eq!(
+            upstream_products(&upstream),
+            [json!([["EPSON-SCP9500", 5]]), json!([["HP-LATEX-800W", 5]])]
+        );
+    }
+
+    #[tokio::test]
+    async fn validates_baskets_without_pricing_them() {
+        let upstream = Upstream::start(testing::fixtures()).await;
+        let state = upstream.state([]);
+
+        let basket = json!({ "EPSON-SCP9500": 1 });
+        let res = testing::send(&state, testing::post("/prices/validate", basket)).await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.body, json!({ "valid": true }));
+
+        let basket = json!({ "UNKNOWN": 1 });
+        let res = testing::send(&state, testing::post("/prices/validate", basket)).await;
+        assert_eq!(res.status, StatusCode::BAD_REQUEST);
+        assert_eq!(res.body["details"][0]["field"], "products.UNKNOWN");
+
+        assert_eq!(upstream.requests_to("/_driverList.asp").len(), 1);
+        assert!(upstream.requests_to("/_prices.asp").is_empty());
+    }
+
+    #[tokio::test]
+    async fn prices_without_waiting_for_drivers() {
+        let upstream = Upstream::start(testing::fixtures()).await;
+        let state = upstream.state([]);
+
+        let basket = json!({ "EPSON-SCP9500": 1 });
+        let res = testing::send(&state, testing::post("/prices", basket)).await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert!(upstream.requests_to("/_driverList.asp").is_empty());
+    }
+
+    #[tokio::test]
+    async fn carries_the_upstream_type() {
+        let state = testing::state([("MOCK_MODE", "true")]);
+        let basket = json!({ "EPSON-SCP9500": 1 });
+
+        let res = testing::send(&state, testing::post("/prices", basket.clone())).await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert!(res.body.get("type").is_none());
+
+        let res = testing::send(&state, testing::post("/prices?include=type", basket)).await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.body["type"], "Subscription");
+    }
+
+    #[tokio::test]
+    async fn summarizes_prices_unless_asked_not_to() {
+        let state = testing::state([("MOCK_MODE", "true")]);
+        let basket = json!({ "EPSON-SCP9500": 1 });
+
+        let res = testing::send(&state, testing::post("/prices", basket.clone())).await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(
+            res.body["monthly"],
+            json!({ "connect": 2990, "production": 8990 })
+        );
+        assert_eq!(
+            res.body["yearly"],
+            json!({ "connect": 2492, "production": 7492 })
+        );
+        assert_eq!(res.body["missing"], json!([]));
+
+        let res = testing::send(&state, testing::post("/prices?summarize=false", basket)).await;
+        assert_eq!(res.status, StatusCode::OK);
+        for key in ["monthly", "yearly", "missing"] {
+            assert!(res.body.get(key).is_none(), "{key} should be left out");
+        }
+        assert_eq!(
+            res.body["tiers"],
+            json!({
+                "connect": { "30": 2990, "365": 29900 },
+                "production": { "30": 8990, "90": 24900, "365": 89900 },
+            })
+        );
+        assert!(res.body["basket"].is_string());
+    }
+
+    #[test]
+    fn compares_decimal_and_float_pricing() {
+        let monthly = |config: &Config, price: f64| {
+            let results = json!([["Connect", 30, 1, price]]);
+            let float = fold_as::<f32>(config, results.clone()).unwrap();
+            let decimal = fold_as::<DecimalPrice>(config, results).unwrap();
+            (float.monthly.connect, decimal.monthly.connect)
+        };
+
+        let config = testing::config([]);
+        assert_eq!(monthly(&config, 29.9), (2990, 2990));
+        // Half a cent, which the float is slightly below of.
+        assert_eq!(monthly(&config, 1.005), (100, 101));
+
+        // Quoted and scientific prices only parse in decimal.
+        for price in [json!("1.005"), json!("100.5e-2")] {
+            let results = json!([["Connect", 30, 1, price]]);
+            let prices = fold_as::<DecimalPrice>(&config, results).unwrap();
+            assert_eq!(prices.monthly.connect, 101);
+        }
+
+        let config = testing::config([("MARKUP_CONNECT", "1.1")]);
+        assert_eq!(monthly(&config, 19.99), (2199, 2199));
+
+        // Overflowing prices saturate rather than panic, then fail the
+        // range check.
+        let config = testing::config([]);
+        let results = json!([["Connect", 30, 1, 1e27], ["Connect", 365, 1, 1e27]]);
+        let prices = fold_as::<DecimalPrice>(&config, results).unwrap();
+        assert_eq!(prices.monthly.connect, i64::MAX);
+        assert_eq!(prices.yearly.connect, i64::MAX);
+        assert_eq!(
+            prices.find_out_of_range(config.prices_max_cents),
+            Some(i64::MAX)
+        );
+
+        let Err(err) = fold_as::<DecimalPrice>(&config, json!([["Connect", 30, 1, "NaN"]])) else {
+            panic!("NaN should be rejected");
+        };
+        assert!(err
+            .to_string()
+            .starts_with("upstream returned invalid prices"));
+    }
+
+    #[test]
+    fn clamps_prices_to_the_floors() {
+        let config = testing::config([
+            ("PRICE_FLOOR_CONNECT", "5000"),
+            ("PRICE_FLOOR_PRODUCTION", "100"),
+        ]);
+        let results = json!([
+            ["Connect", 30, 1, 29.9],
+            ["Connect", 365, 1, 299.0],
+            ["Production", 30, 1, 0.0],
+        ]);
+        let mut prices = fold(&config, results).unwrap();
+
+        let (logs, _guard) = Logs::capture();
+        prices.apply_floors(&config);
+
+        assert_eq!(prices.monthly.connect, 5000);
+        assert_eq!(prices.yearly.connect, 5000);
+        assert_eq!(prices.tiers.connect.get(&365), Some(&29900));
+        assert_eq!(prices.monthly.production, 100);
+        // Missing prices are not made up.
+        assert_eq!(prices.yearly.production, 0);
+
+        let logs = logs.contents();
+        assert!(logs.contains("WARN"), "{logs}");
+        assert!(
+            logs.contains("raising monthly.connect price from 2990 to the 5000 cents floor"),
+            "{logs}"
+        );
+        assert!(
+            logs.contains("raising production tier 30 price from 0 to the 100 cents floor"),
+            "{logs}"
+        );
+        assert!(!logs.contains("connect tier 365"), "{logs}");
+    }
+
+    #[tokio::test]
+    async fn passes_raw_prices_through() {
+        let payload = "{\"Type\":  \"Subscription\",\n\"Results\": [[\"Connect\", 30, 1, 29.90]]}";
+        let routes = testing::drivers().route(
+            "/_prices.asp",
+            routing::post(move || async move { payload }),
+        );
+        let upstream = Upstream::start(routes).await;
+        let state = upstream.state([]);
+
+        let basket = json!({ "EPSON-SCP9500": 1 });
+        let res = testing::send(&state, testing::post("/prices/raw", basket)).await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.header("content-type"), Some("application/json"));
+        assert_eq!(res.bytes, payload.as_bytes());
+
+        let failing = routing::post(|| async { (StatusCode::INTERNAL_SERVER_ERROR, "oops") });
+        let upstream = Upstream::start(testing::drivers().route("/_prices.asp", failing)).await;
+        let state = upstream.state([]);
+
+        let basket = json!({ "EPSON-SCP9500": 1 });
+        let res = testing::send(&state, testing::post("/prices/raw", basket)).await;
+        assert_eq!(res.status, StatusCode::BAD_GATEWAY);
+        assert_eq!(
+            res.body["error"],
+            "upstream answered 500 Internal Server Error"
+        );
+    }
+}
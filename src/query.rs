@@ -0,0 +1,77 @@
+//! Query string extraction with explicit semantics for repeated
+//! parameters: single-value parameters keep the last occurrence, so
+//! that `?format=cents&format=localized` is localized, while
+//! multi-value parameters collect every occurrence, so that
+//! `?include=a&include=b` includes both.
+
+use anyhow::anyhow;
+use axum::{
+    extract::FromRequestParts,
+    http::{request::Parts, StatusCode},
+};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer};
+
+use crate::error::Error;
+
+/// Same as the axum `Query` extractor, except that parameters can be
+/// repeated. Multi-value parameters are deserialized as `Vec`, single
+/// value ones must use [`last`].
+pub struct Query<T>(pub T);
+
+impl<T: DeserializeOwned, S: Send + Sync> FromRequestParts<S> for Query<T> {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let query = parts.uri.query().unwrap_or_default();
+        match serde_html_form::from_str(query) {
+            Ok(query) => Ok(Self(query)),
+            Err(err) => Err(Error::new(
+                StatusCode::BAD_REQUEST,
+                anyhow!("invalid query string: {err}"),
+            )),
+        }
+    }
+}
+
+/// Deserializes a single-value parameter from its last occurrence, to
+/// be used with `#[serde(default, deserialize_with = "query::last")]`.
+pub fn last<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + Default,
+{
+    let mut values = Vec::<T>::deserialize(deserializer)?;
+    Ok(values.pop().unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    struct TestQuery {
+        #[serde(default, deserialize_with = "super::last")]
+        format: Option<String>,
+        #[serde(default)]
+        include: Vec<String>,
+    }
+
+    fn parse(query: &str) -> TestQuery {
+        serde_html_form::from_str(query).unwrap()
+    }
+
+    #[test]
+    fn keeps_the_last_single_value() {
+        assert_eq!(parse("format=cents").format.as_deref(), Some("cents"));
+        let query = parse("format=cents&include=type&format=localized");
+        assert_eq!(query.format.as_deref(), Some("localized"));
+        assert_eq!(parse("").format, None);
+    }
+
+    #[test]
+    fn collects_every_multi_value() {
+        let query = parse("include=type&format=cents&include=fetched_at");
+        assert_eq!(query.include, ["type", "fetched_at"]);
+        assert!(parse("").include.is_empty());
+    }
+}
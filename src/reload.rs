@@ -0,0 +1,236 @@
+use std::sync::Arc;
+
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{debug, error, info, warn};
+
+use crate::{config::Config, state::AppState};
+
+/// Reloads the configuration on every `SIGHUP`, see `CONFIG_FILE`.
+/// Requests pick the new configuration up as they come, settings only
+/// applied at startup are logged as requiring a restart.
+pub fn spawn_reloader(state: Arc<AppState>) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(err) => {
+            warn!("cannot listen for SIGHUP, config reload disabled: {err}");
+            return;
+        }
+    };
+
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            reload(&state);
+        }
+    });
+}
+
+fn reload(state: &AppState) {
+    match Config::load() {
+        Ok(config) => apply(state, config),
+        Err(err) => error!("cannot reload config, keeping the current one: {err}"),
+    }
+}
+
+/// Swaps the configuration in effect for the given one. Settings only
+/// applied at startup are compared against the startup configuration,
+/// so that they keep being reported until the server restarts.
+fn apply(state: &AppState, config: Config) {
+    for key in state.startup_config().restart_required(&config) {
+        warn!("{key} changed, restart for the change to apply");
+    }
+
+    let changes = state.config().pricing_changes(&config);
+
+    debug!("reloaded {config:?}");
+    state.set_config(config);
+    info!("config reloaded");
+
+    // Cleared once the new configuration is in effect, so that prices
+    // are not fetched again with the previous one.
+    if !changes.is_empty() {
+        info!("{} changed, clearing prices cache", changes.join(", "));
+        state.prices.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::{
+        http::{header::ORIGIN, Request, StatusCode},
+        routing,
+    };
+    use serde_json::json;
+    use tokio::time;
+
+    use crate::{
+        cache::Lookup,
+        testing::{self, Upstream},
+    };
+
+    use super::apply;
+
+    #[tokio::test]
+    async fn applies_the_new_config() {
+        let state = testing::state([("MOCK_MODE", "true")]);
+        let req = testing::post("/prices", json!({ "EPSON-SCP9500": 1 }));
+        let res = testing::send(&state, req).await;
+        assert_eq!(res.status, StatusCode::OK);
+        let key = res.header("x-basket-hash").unwrap().to_owned();
+
+        apply(
+            &state,
+            testing::config([
+                ("MOCK_MODE", "true"),
+                ("MARKUP_CONNECT", "1.1"),
+                ("CORS_ORIGINS", "https://example.com"),
+                ("PORT", "4000"),
+            ]),
+        );
+
+        // Cached prices were computed with the previous markup.
+        assert!(matches!(
+            state.prices.get(&key, &state.config()),
+            Lookup::Miss
+        ));
+
+        let req = Request::get("/drivers")
+            .header(ORIGIN, "https://example.com")
+            .body(Default::default())
+            .unwrap();
+        let res = testing::send(&state, req).await;
+        let origin = res.header("access-control-allow-origin");
+        assert_eq!(origin, Some("https://example.com"));
+
+        let req = Request::get("/drivers")
+            .header(ORIGIN, "https://bolt.new")
+            .body(Default::default())
+            .unwrap();
+        let res = testing::send(&state, req).await;
+        assert_eq!(res.header("access-control-allow-origin"), None);
+    }
+
+    #[tokio::test]
+    async fn compares_restart_settings_against_startup() {
+        let state = testing::state([("MOCK_MODE", "true")]);
+        let changed = || testing::config([("MOCK_MODE", "true"), ("PORT", "4000")]);
+
+        apply(&state, changed());
+        apply(&state, changed());
+
+        let restart = state.startup_config().restart_required(&state.config());
+        assert_eq!(restart, ["PORT"]);
+        assert_eq!(state.config().port, 4000);
+    }
+
+    #[tokio::test]
+    async fn keeps_the_startup_timeouts() {
+        let slow = routing::post(|| async {
+            time::sleep(Duration::from_millis(1500)).await;
+            testing::PRICES
+        });
+        let upstream = Upstream::start(testing::drivers().route("/_prices.asp", slow)).await;
+        let vars = [("REQUEST_TIMEOUT", "10"), ("UPSTREAM_TIMEOUT", "10")];
+        let state = upstream.state(vars);
+
+        let base = upstream.base();
+        let reloaded = [
+            ("UPSTREAM_BASE", base.as_str()),
+            ("RETRY_ATTEMPTS", "0"),
+            ("REQUEST_TIMEOUT", "1"),
+            ("UPSTREAM_TIMEOUT", "1"),
+        ];
+        apply(&state, testing::config(reloaded));
+        assert_eq!(state.config().request_timeout, Duration::from_secs(1));
+
+        // Neither the deadline nor the upstream timeout shrank.
+        let res = testing::send(&state, testing::get("/prices/EPSON-SCP9500")).await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.header("x-server-timeout-ms"), Some("10000"));
+
+        let res = testing::send(&state, testing::get("/capabilities")).await;
+        assert_eq!(res.body["request_timeout_secs"], 10);
+    }
+
+    #[tokio::test]
+    async fn clears_prices_fetched_from_fixtures() {
+        let upstream = Upstream::start(testing::fixtures()).await;
+        let state = upstream.state([("MOCK_MODE", "true")]);
+        let req = testing::post("/prices", json!({ "EPSON-SCP9500": 1 }));
+        let res = testing::send(&state, req).await;
+        let key = res.header("x-basket-hash").unwrap().to_owned();
+
+        let base = upstream.base();
+        apply(&state, testing::config([("UPSTREAM_BASE", base.as_str())]));
+        assert!(matches!(
+            state.prices.get(&key, &state.config()),
+            Lookup::Miss
+        ));
+    }
+
+    #[tokio::test]
+    async fn drops_prices_fetched_with_the_previous_config() {
+        let slow = routing::post(|| async {
+            time::sleep(Duration::from_millis(300)).await;
+            testing::PRICES
+        });
+        let upstream = Upstream::start(testing::drivers().route("/_prices.asp", slow)).await;
+        let state = upstream.state([]);
+
+        let base = upstream.base();
+        let reloaded = [
+            ("UPSTREAM_BASE", base.as_str()),
+            ("RETRY_ATTEMPTS", "0"),
+            ("MARKUP_CONNECT", "1.1"),
+        ];
+        let req = testing::post("/prices", json!({ "EPSON-SCP9500": 1 }));
+        let reload = async {
+            time::sleep(Duration::from_millis(100)).await;
+            apply(&state, testing::config(reloaded));
+        };
+        let (res, ()) = tokio::join!(testing::send(&state, req), reload);
+        assert_eq!(res.status, StatusCode::OK);
+
+        // Priced with the previous markup, hence not cached.
+        let key = res.header("x-basket-hash").unwrap();
+        assert!(matches!(
+            state.prices.get(key, &state.config()),
+            Lookup::Miss
+        ));
+    }
+
+    #[tokio::test]
+    async fn applies_stale_settings_live() {
+        let vars = [("MOCK_MODE", "true"), ("PRICES_CACHE_TTL", "0")];
+        let state = testing::state(vars);
+        let basket = json!({ "EPSON-SCP9500": 1 });
+        let res = testing::send(&state, testing::post("/prices", basket.clone())).await;
+        let key = res.header("x-basket-hash").unwrap().to_owned();
+
+        let reloaded = [
+            ("MOCK_MODE", "true"),
+            ("PRICES_CACHE_TTL", "0"),
+            ("PRICES_CACHE_STALE_MAX_AGE", "42"),
+        ];
+        apply(&state, testing::config(reloaded));
+        let restart = state.startup_config().restart_required(&state.config());
+        assert!(restart.is_empty(), "{restart:?}");
+
+        let res = testing::send(&state, testing::post("/prices", basket)).await;
+        assert_eq!(res.header("x-cache"), Some("STALE"));
+        let cache_control = res.header("cache-control").unwrap();
+        assert!(cache_control.contains("max-age=42"), "{cache_control}");
+
+        let reloaded = [
+            ("MOCK_MODE", "true"),
+            ("PRICES_CACHE_TTL", "0"),
+            ("PRICES_CACHE_STALE", "0"),
+        ];
+        apply(&state, testing::config(reloaded));
+        assert!(matches!(
+            state.prices.get(&key, &state.config()),
+            Lookup::Miss
+        ));
+    }
+}
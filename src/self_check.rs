@@ -0,0 +1,43 @@
+use std::process;
+
+use tracing::{error, info, warn};
+
+use crate::{
+    drivers,
+    error::Error,
+    prices::{self, DEFAULT_CURRENCY},
+    state::AppState,
+    upstream::Context,
+};
+
+/// Fetches the drivers and prices the first of them, so that upstream
+/// schema changes are caught at startup rather than by clients. Exits
+/// on failure unless `SELF_CHECK_STRICT` is disabled, in which case the
+/// server starts degraded.
+pub async fn run(state: &AppState) {
+    match check(state).await {
+        Ok(()) => info!("upstream self check passed"),
+        Err(err) if state.config().self_check_strict => {
+            error!("upstream self check failed: {err}");
+            process::exit(1);
+        }
+        Err(err) => {
+            warn!("upstream self check failed, starting degraded: {err}");
+            *state.degraded.write().unwrap() = Some(err.to_string());
+        }
+    }
+}
+
+async fn check(state: &AppState) -> Result<(), Error> {
+    let cache = drivers::refresh_drivers(state).await?;
+
+    let Some(driver) = cache.drivers.0.first() else {
+        warn!("upstream returned no drivers, skipping prices self check");
+        return Ok(());
+    };
+
+    let products = vec![(driver.code.clone(), 1)];
+    let ctx = Context::default();
+    prices::fetch_upstream_prices(state, &ctx, products, DEFAULT_CURRENCY).await?;
+    Ok(())
+}
@@ -0,0 +1,108 @@
+use std::sync::{Arc, RwLock};
+
+use arc_swap::ArcSwap;
+use reqwest::Client;
+use tokio::sync::{Mutex, Notify, Semaphore};
+
+use crate::{
+    cache::PricesCache,
+    config::Config,
+    dns::{ConnectionStats, TimedResolver},
+    drivers::DriversCache,
+    graphql::{self, GraphqlSchema},
+    metrics::Metrics,
+    prefetch::Prefetches,
+    prices::PendingFetches,
+    upstream::{HostPermits, Throttle, UpstreamHealth},
+};
+
+pub struct AppState {
+    /// Swapped on reload, see [`crate::reload`]. Read through
+    /// [`AppState::config`].
+    config: ArcSwap<Config>,
+    /// Configuration the server was started with, which settings only
+    /// applied at startup still follow.
+    startup_config: Arc<Config>,
+    pub client: Client,
+    pub connections: Arc<ConnectionStats>,
+    pub drivers: RwLock<Option<Arc<DriversCache>>>,
+    /// Held while fetching the drivers on a cold cache, see
+    /// [`crate::drivers::cached_drivers`].
+    pub drivers_fetch: Mutex<()>,
+    /// Wakes up the drivers long polls whenever the list changes.
+    pub drivers_changed: Notify,
+    pub graphql: GraphqlSchema,
+    pub throttle: Throttle,
+    pub upstream_health: UpstreamHealth,
+    pub prices: PricesCache,
+    pub pending_fetches: PendingFetches,
+    pub metrics: Metrics,
+    /// Permits for upstream-bound requests, see [`crate::middleware::shed_load`].
+    pub in_flight: Semaphore,
+    /// Permits `in_flight` was created with.
+    pub max_in_flight: usize,
+    /// Permits for upstream calls, see [`crate::upstream::send`].
+    pub upstream_permits: Semaphore,
+    pub host_permits: HostPermits,
+    /// Why the startup self check failed, when the server was started
+    /// degraded.
+    pub degraded: RwLock<Option<String>>,
+    pub prefetches: Prefetches,
+}
+
+impl AppState {
+    /// Returns the configuration in effect.
+    pub fn config(&self) -> Arc<Config> {
+        self.config.load_full()
+    }
+
+    pub fn startup_config(&self) -> &Config {
+        &self.startup_config
+    }
+
+    pub fn set_config(&self, config: Config) {
+        self.config.store(Arc::new(config));
+    }
+
+    pub fn new(config: Config) -> Self {
+        let connections = Arc::new(ConnectionStats::default());
+        let client = Client::builder()
+            .dns_resolver(Arc::new(TimedResolver(connections.clone())))
+            .timeout(config.upstream_timeout)
+            .pool_max_idle_per_host(config.upstream_concurrency_per_host)
+            // The negotiated version is not logged: reqwest only exposes
+            // the peer certificate of a connection, see `tls::TlsInfo`.
+            .min_tls_version(config.upstream_min_tls.version())
+            .build()
+            .expect("should build HTTP client");
+
+        let prices = PricesCache::new(&config);
+        let in_flight = Semaphore::new(config.max_in_flight);
+        let upstream_permits = Semaphore::new(config.upstream_concurrency);
+        let host_permits = HostPermits::new(config.upstream_concurrency_per_host);
+        let max_in_flight = config.max_in_flight;
+        let config = Arc::new(config);
+
+        Self {
+            config: ArcSwap::new(config.clone()),
+            startup_config: config,
+            client,
+            connections,
+            drivers: RwLock::new(None),
+            drivers_fetch: Mutex::new(()),
+            drivers_changed: Notify::new(),
+            graphql: graphql::schema(),
+            throttle: Throttle::default(),
+            upstream_health: UpstreamHealth::default(),
+            prices,
+            pending_fetches: PendingFetches::default(),
+            metrics: Metrics::default(),
+            in_flight,
+            max_in_flight,
+            upstream_permits,
+            host_permits,
+            degraded: RwLock::new(None),
+            prefetches: Prefetches::default(),
+        }
+    }
+}
@@ -0,0 +1,203 @@
+//! Helpers shared by the tests: building the app from a handful of
+//! variables, sending requests to it and faking upstream.
+
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    body::{to_bytes, Body, Bytes},
+    extract::{Request, State},
+    http::{header::CONTENT_TYPE, HeaderMap, Method, StatusCode, Uri},
+    middleware::{self, Next},
+    response::Response,
+    routing, Router,
+};
+use serde_json::Value;
+use tokio::net::TcpListener;
+use tower::ServiceExt;
+use tracing::subscriber::DefaultGuard;
+
+use crate::{
+    config::{Config, Vars},
+    state::AppState,
+};
+
+pub static DRIVERS: &str = include_str!("../fixtures/drivers.json");
+pub static PRICES: &str = include_str!("../fixtures/prices.json");
+
+/// Builds the configuration from the given variables only, ignoring
+/// the environment.
+pub fn config<'a>(vars: impl IntoIterator<Item = (&'a str, &'a str)>) -> Config {
+    let vars: Vars = vars.into_iter().collect();
+    Config::from_vars(&vars).expect("should be a valid configuration")
+}
+
+pub fn state<'a>(vars: impl IntoIterator<Item = (&'a str, &'a str)>) -> Arc<AppState> {
+    Arc::new(AppState::new(config(vars)))
+}
+
+pub struct TestResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    /// The body parsed as JSON, null when empty or not JSON.
+    pub body: Value,
+    /// The body as received.
+    pub bytes: Bytes,
+}
+
+impl TestResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|value| value.to_str().ok())
+    }
+}
+
+/// Sends the given request through the whole app.
+pub async fn send(state: &Arc<AppState>, req: Request) -> TestResponse {
+    let res = crate::app(state.clone())
+        .oneshot(req)
+        .await
+        .expect("should answer");
+
+    let (parts, body) = res.into_parts();
+    let bytes = to_bytes(body, usize::MAX)
+        .await
+        .expect("should read the body");
+
+    TestResponse {
+        status: parts.status,
+        headers: parts.headers,
+        body: serde_json::from_slice(&bytes).unwrap_or_default(),
+        bytes,
+    }
+}
+
+pub fn get(uri: &str) -> Request {
+    Request::get(uri).body(Body::empty()).unwrap()
+}
+
+pub fn post(uri: &str, body: Value) -> Request {
+    Request::post(uri)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+/// Request received by a fake upstream.
+#[derive(Clone)]
+pub struct Recorded {
+    pub method: Method,
+    pub uri: Uri,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+/// Fake upstream listening on a local port, recording the requests it
+/// receives.
+pub struct Upstream {
+    pub addr: SocketAddr,
+    requests: Arc<Mutex<Vec<Recorded>>>,
+}
+
+impl Upstream {
+    /// Serves the given routes, see [`fixtures`] for the usual ones.
+    pub async fn start(routes: Router) -> Self {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let app = routes.layer(middleware::from_fn_with_state(requests.clone(), record));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        Self { addr, requests }
+    }
+
+    /// Value of `UPSTREAM_BASE` pointing to this upstream.
+    pub fn base(&self) -> String {
+        format!("http://{}/", self.addr)
+    }
+
+    /// Builds the state of an app calling this upstream, without
+    /// retries unless the given variables say otherwise.
+    pub fn state<'a>(&self, vars: impl IntoIterator<Item = (&'a str, &'a str)>) -> Arc<AppState> {
+        let base = self.base();
+        let vars: Vec<_> = vars.into_iter().collect();
+        let defaults = [("UPSTREAM_BASE", base.as_str()), ("RETRY_ATTEMPTS", "0")];
+        state(defaults.into_iter().chain(vars))
+    }
+
+    pub fn requests(&self) -> Vec<Recorded> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Requests received on the given path.
+    pub fn requests_to(&self, path: &str) -> Vec<Recorded> {
+        let mut requests = self.requests();
+        requests.retain(|req| req.uri.path() == path);
+        requests
+    }
+}
+
+async fn record(
+    State(requests): State<Arc<Mutex<Vec<Recorded>>>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let (parts, body) = req.into_parts();
+    let body = to_bytes(body, usize::MAX).await.unwrap_or_default();
+
+    requests.lock().unwrap().push(Recorded {
+        method: parts.method.clone(),
+        uri: parts.uri.clone(),
+        headers: parts.headers.clone(),
+        body: body.clone(),
+    });
+
+    next.run(Request::from_parts(parts, Body::from(body))).await
+}
+
+/// Upstream routes answering with the bundled fixtures.
+pub fn fixtures() -> Router {
+    drivers().route("/_prices.asp", routing::post(|| async { PRICES }))
+}
+
+/// Upstream drivers route answering with the bundled fixture, for
+/// tests faking the prices route.
+pub fn drivers() -> Router {
+    Router::new().route("/_driverList.asp", routing::get(|| async { DRIVERS }))
+}
+
+/// Logs emitted while captured, see [`Logs::capture`].
+#[derive(Clone, Default)]
+pub struct Logs(Arc<Mutex<Vec<u8>>>);
+
+impl Logs {
+    /// Captures the logs of the current thread until the returned guard
+    /// is dropped.
+    pub fn capture() -> (Self, DefaultGuard) {
+        let logs = Self::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+        (logs, tracing::subscriber::set_default(subscriber))
+    }
+
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+    }
+}
+
+impl io::Write for Logs {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,657 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::anyhow;
+use axum::{
+    extract::FromRequestParts,
+    http::{
+        header::{ACCEPT, RETRY_AFTER},
+        request::Parts,
+        HeaderMap, HeaderValue, StatusCode,
+    },
+};
+use reqwest::{RequestBuilder, Response, Url};
+use tokio::{
+    sync::{AcquireError, OwnedSemaphorePermit, Semaphore},
+    time,
+};
+use tracing::{debug, warn};
+
+use crate::{
+    config::{ApiKeyLocation, Config},
+    error::Error,
+    state::AppState,
+};
+
+/// Used when upstream throttles us without telling for how long.
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Remembers until when upstream asked us to back off, so that
+/// requests are not sent while we are throttled.
+#[derive(Default)]
+pub struct Throttle(Mutex<Option<Instant>>);
+
+impl Throttle {
+    pub fn remaining(&self) -> Option<Duration> {
+        let until = (*self.0.lock().unwrap())?;
+        until
+            .checked_duration_since(Instant::now())
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    fn throttle(&self, duration: Duration) {
+        *self.0.lock().unwrap() = Some(Instant::now() + duration);
+    }
+}
+
+fn parse_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let retry_after = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+
+    if let Ok(secs) = retry_after.parse() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let date = httpdate::parse_http_date(retry_after).ok()?;
+    Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+#[derive(Clone)]
+pub struct UpstreamCheck {
+    pub at: SystemTime,
+    /// Why upstream could not serve the call, if it could not.
+    pub error: Option<String>,
+}
+
+/// Remembers the outcome of the last upstream call, for health
+/// reporting.
+#[derive(Default)]
+pub struct UpstreamHealth(Mutex<Option<UpstreamCheck>>);
+
+impl UpstreamHealth {
+    fn record(&self, error: Option<String>) {
+        let check = UpstreamCheck {
+            at: SystemTime::now(),
+            error,
+        };
+        *self.0.lock().unwrap() = Some(check);
+    }
+
+    pub fn last(&self) -> Option<UpstreamCheck> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// Permits for upstream calls per host, see
+/// `MAX_UPSTREAM_CONCURRENCY_PER_HOST`. Each host gets its own budget,
+/// created on its first call.
+pub struct HostPermits {
+    per_host: usize,
+    hosts: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl HostPermits {
+    pub fn new(per_host: usize) -> Self {
+        Self {
+            per_host,
+            hosts: Mutex::default(),
+        }
+    }
+
+    async fn acquire(&self, host: &str) -> Result<OwnedSemaphorePermit, AcquireError> {
+        let permits = self
+            .hosts
+            .lock()
+            .unwrap()
+            .entry(host.to_owned())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.per_host)))
+            .clone();
+        permits.acquire_owned().await
+    }
+}
+
+fn throttled(retry_after: Duration) -> Error {
+    Error::new(
+        StatusCode::SERVICE_UNAVAILABLE,
+        anyhow!("upstream is throttling requests"),
+    )
+    .retry_after(retry_after)
+}
+
+static TRACEPARENT: &str = "traceparent";
+static X_REQUEST_ID: &str = "x-request-id";
+
+/// Request-scoped data attached to upstream requests.
+#[derive(Clone, Default)]
+pub struct Context {
+    /// Trace headers to forward upstream, see `FORWARD_TRACE_HEADERS`.
+    trace_headers: HeaderMap,
+    /// The client `X-Request-Id`, or the generated one when trace
+    /// headers are forwarded.
+    request_id: Option<String>,
+    /// When the client stops waiting, per `REQUEST_TIMEOUT` as of
+    /// startup like the timeout layer. Background calls have none.
+    deadline: Option<Instant>,
+}
+
+impl Context {
+    pub fn request_id(&self) -> Option<&str> {
+        self.request_id.as_deref()
+    }
+
+    /// Returns the time left before the deadline, if any.
+    fn remaining(&self) -> Option<Duration> {
+        let deadline = self.deadline?;
+        Some(deadline.saturating_duration_since(Instant::now()))
+    }
+}
+
+impl FromRequestParts<Arc<AppState>> for Context {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let mut trace_headers = HeaderMap::new();
+
+        if state.config().forward_trace_headers {
+            let traceparent = match parts.headers.get(TRACEPARENT) {
+                Some(traceparent) => traceparent.clone(),
+                None => {
+                    let traceparent = format!(
+                        "00-{:032x}-{:016x}-01",
+                        rand::random::<u128>(),
+                        rand::random::<u64>()
+                    );
+                    HeaderValue::try_from(traceparent).expect("should be a valid header")
+                }
+            };
+
+            let request_id = match parts.headers.get(X_REQUEST_ID) {
+                Some(request_id) => request_id.clone(),
+                None => HeaderValue::try_from(format!("{:032x}", rand::random::<u128>()))
+                    .expect("should be a valid header"),
+            };
+
+            trace_headers.insert(TRACEPARENT, traceparent);
+            trace_headers.insert(X_REQUEST_ID, request_id);
+        }
+
+        let request_id = trace_headers
+            .get(X_REQUEST_ID)
+            .or_else(|| parts.headers.get(X_REQUEST_ID))
+            .and_then(|request_id| request_id.to_str().ok())
+            .map(String::from);
+
+        Ok(Self {
+            trace_headers,
+            request_id,
+            deadline: Some(Instant::now() + state.startup_config().request_timeout),
+        })
+    }
+}
+
+/// Sends the given upstream request, unless upstream asked us to back
+/// off with a `429 Too Many Requests`. Transport errors, truncated
+/// bodies included, and server errors are retried up to
+/// `RETRY_ATTEMPTS` times, following `RETRY_BACKOFF`.
+pub async fn send(
+    state: &AppState,
+    ctx: &Context,
+    mut req: RequestBuilder,
+) -> Result<Response, Error> {
+    let config = &state.config();
+    let mut retry = 0;
+
+    loop {
+        // Requests with a streaming body cannot be cloned, hence not
+        // retried.
+        let next = req.try_clone();
+        let res = send_once(state, ctx, req).await?;
+
+        let failed = match &res {
+            Ok(res) => res.status().is_server_error(),
+            Err(_) => true,
+        };
+
+        match next {
+            Some(next) if failed && retry < config.retry_attempts => {
+                let delay = config.retry_backoff.delay(
+                    retry,
+                    config.retry_base_delay,
+                    config.retry_max_delay,
+                );
+                warn!("upstream call failed, retrying in {delay:?}");
+                time::sleep(delay).await;
+                retry += 1;
+                req = next;
+            }
+            _ => return res.map_err(transport_error),
+        }
+    }
+}
+
+/// Sends the given upstream request once. Transport errors are
+/// returned in the inner result, so that they can be retried.
+///
+/// Waits for one of the `MAX_UPSTREAM_CONCURRENCY_PER_HOST` permits of
+/// the upstream host first, then for one of the `UPSTREAM_CONCURRENCY`
+/// ones. Permits are only held while sending, never across other
+/// upstream calls, so that handlers making several calls concurrently
+/// cannot deadlock waiting for each other's permits: with a single
+/// permit, their calls are just made one after the other.
+async fn send_once(
+    state: &AppState,
+    ctx: &Context,
+    req: RequestBuilder,
+) -> Result<Result<Response, reqwest::Error>, Error> {
+    if let Some(remaining) = state.throttle.remaining() {
+        return Err(throttled(remaining));
+    }
+
+    let (client, req) = req.build_split();
+    let req = req?;
+    let host = req.url().host_str().unwrap_or_default().to_owned();
+    let req = RequestBuilder::from_parts(client, req);
+
+    let _host_permit = state.host_permits.acquire(&host).await?;
+    let _permit = state.upstream_permits.acquire().await?;
+
+    // Waiting on upstream past the request deadline is pointless, the
+    // client already got a 504.
+    let req = match ctx.remaining() {
+        Some(remaining) if remaining.is_zero() => {
+            return Err(Error::new(
+                StatusCode::GATEWAY_TIMEOUT,
+                anyhow!("request timed out"),
+            ));
+        }
+        Some(remaining) => req.timeout(remaining.min(state.startup_config().upstream_timeout)),
+        None => req,
+    };
+
+    let req = match &state.config().upstream_api_key {
+        Some(key) => with_api_key(&state.config(), req, key.expose()),
+        None => req,
+    };
+
+    let req = req
+        .header(ACCEPT, &state.config().upstream_accept)
+        .headers(ctx.trace_headers.clone());
+
+    #[cfg(feature = "fault-injection")]
+    if rand::random::<f64>() < state.config().test_failure_rate {
+        debug!("injecting upstream failure");
+        let res = axum::http::Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .body("injected failure")
+            .expect("should be a valid response");
+        state
+            .upstream_health
+            .record(Some("injected failure".into()));
+        return Ok(Ok(res.into()));
+    }
+
+    let resolutions = state.connections.call_started();
+    let res = match req.send().await {
+        Ok(res) => buffer(res).await,
+        Err(err) => Err(err),
+    };
+    state.connections.call_ended(resolutions);
+
+    let res = match res {
+        Ok(res) => res,
+        Err(err) => {
+            // The URL may carry the API key, it must not end up in logs
+            // nor in responses.
+            let err = err.without_url();
+            state.upstream_health.record(Some(err.to_string()));
+            return Ok(Err(err));
+        }
+    };
+
+    let status = res.status();
+    let error = status
+        .is_server_error()
+        .then(|| format!("upstream answered {status}"));
+    state.upstream_health.record(error);
+
+    if res.status() == StatusCode::TOO_MANY_REQUESTS {
+        let retry_after = parse_retry_after(res.headers()).unwrap_or(DEFAULT_RETRY_AFTER);
+        warn!("throttled by upstream for {retry_after:?}");
+        state.throttle.throttle(retry_after);
+        return Err(throttled(retry_after));
+    }
+
+    Ok(Ok(res))
+}
+
+/// Reads the whole response body, so that a connection dropped
+/// mid-body fails the call and gets retried like a transport error.
+async fn buffer(res: Response) -> Result<Response, reqwest::Error> {
+    let mut buffered = axum::http::Response::builder()
+        .status(res.status())
+        .version(res.version());
+
+    if let Some(headers) = buffered.headers_mut() {
+        *headers = res.headers().clone();
+    }
+
+    let body = res.bytes().await?;
+    Ok(buffered
+        .body(body)
+        .expect("should be a valid response")
+        .into())
+}
+
+/// Answers truncated upstream bodies with a 502, other transport
+/// errors with a 500.
+fn transport_error(err: reqwest::Error) -> Error {
+    if (err.is_body() || err.is_decode()) && !err.is_timeout() {
+        warn!("upstream response was truncated: {err}");
+        Error::new(
+            StatusCode::BAD_GATEWAY,
+            anyhow!("truncated upstream response"),
+        )
+    } else {
+        Error::from(err)
+    }
+}
+
+/// Attaches the upstream API key where `UPSTREAM_API_KEY_IN` says.
+pub fn with_api_key(config: &Config, req: RequestBuilder, key: &str) -> RequestBuilder {
+    let name = config.upstream_api_key_name.as_str();
+
+    match config.upstream_api_key_location {
+        ApiKeyLocation::Header => match HeaderValue::from_str(key) {
+            Ok(mut key) => {
+                key.set_sensitive(true);
+                req.header(name, key)
+            }
+            Err(_) => {
+                warn!("UPSTREAM_API_KEY is not a valid header value, not sending it");
+                req
+            }
+        },
+        ApiKeyLocation::Query => req.query(&[(name, key)]),
+    }
+}
+
+/// Primes the connection pool with a cheap request, so that the first
+/// client request does not pay for the TLS handshake. The request is
+/// built like any other upstream one, with the API key and the accept
+/// header. Failures are only logged, and upstream is left alone in mock
+/// mode.
+pub async fn warm_up(state: &AppState, url: Url) {
+    if state.config().mock_mode {
+        debug!("mock mode enabled, not warming up upstream connection");
+        return;
+    }
+
+    let req = state.client.head(url);
+    match send_once(state, &Context::default(), req).await {
+        Ok(Ok(res)) => debug!("upstream connection warmed up ({})", res.status()),
+        Ok(Err(err)) => warn!("cannot warm up upstream connection: {err}"),
+        Err(err) => warn!("cannot warm up upstream connection: {err}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+        time::{Duration, Instant, SystemTime},
+    };
+
+    use axum::{
+        http::{header::RETRY_AFTER, HeaderMap, Request, StatusCode},
+        response::IntoResponse,
+        routing, Router,
+    };
+    use tokio::time;
+
+    use crate::testing::{self, Upstream};
+
+    use super::{parse_retry_after, send, warm_up, Context, HostPermits};
+
+    static TRACEPARENT: &str = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+
+    #[tokio::test]
+    async fn forwards_trace_headers() {
+        let upstream = Upstream::start(testing::fixtures()).await;
+        let state = upstream.state([("FORWARD_TRACE_HEADERS", "true")]);
+
+        let req = Request::get("/prices/EPSON-SCP9500")
+            .header("traceparent", TRACEPARENT)
+            .body(Default::default())
+            .unwrap();
+        let res = testing::send(&state, req).await;
+        assert_eq!(res.status, StatusCode::OK);
+
+        let requests = upstream.requests_to("/_prices.asp");
+        let headers = &requests[0].headers;
+        assert_eq!(headers["traceparent"], TRACEPARENT);
+        let request_id = headers["x-request-id"].to_str().unwrap();
+        assert_eq!(request_id.len(), 32);
+    }
+
+    #[tokio::test]
+    async fn does_not_forward_trace_headers_by_default() {
+        let upstream = Upstream::start(testing::fixtures()).await;
+        let state = upstream.state([]);
+
+        let req = Request::get("/prices/EPSON-SCP9500")
+            .header("traceparent", TRACEPARENT)
+            .body(Default::default())
+            .unwrap();
+        testing::send(&state, req).await;
+
+        let requests = upstream.requests_to("/_prices.asp");
+        assert!(!requests[0].headers.contains_key("traceparent"));
+        assert!(!requests[0].headers.contains_key("x-request-id"));
+    }
+
+    #[tokio::test]
+    async fn attaches_the_api_key() {
+        let upstream = Upstream::start(testing::fixtures()).await;
+
+        let state = upstream.state([("UPSTREAM_API_KEY", "s3cret")]);
+        let res = testing::send(&state, testing::get("/prices/EPSON-SCP9500")).await;
+        assert_eq!(res.status, StatusCode::OK);
+
+        let vars = [
+            ("UPSTREAM_API_KEY", "s3cret"),
+            ("UPSTREAM_API_KEY_IN", "query"),
+        ];
+        let state = upstream.state(vars);
+        let res = testing::send(&state, testing::get("/prices/EPSON-SCP9500")).await;
+        assert_eq!(res.status, StatusCode::OK);
+
+        let requests = upstream.requests_to("/_prices.asp");
+        assert_eq!(requests[0].headers["x-api-key"], "s3cret");
+        assert_eq!(requests[0].uri.query(), None);
+        assert!(!requests[1].headers.contains_key("x-api-key"));
+        assert_eq!(requests[1].uri.query(), Some("api_key=s3cret"));
+    }
+
+    #[tokio::test]
+    async fn sends_no_api_key_by_default() {
+        let upstream = Upstream::start(testing::fixtures()).await;
+        let state = upstream.state([]);
+
+        testing::send(&state, testing::get("/prices/EPSON-SCP9500")).await;
+
+        let requests = upstream.requests_to("/_prices.asp");
+        assert!(!requests[0].headers.contains_key("x-api-key"));
+        assert_eq!(requests[0].uri.query(), None);
+    }
+
+    #[tokio::test]
+    async fn warms_up_like_any_upstream_request() {
+        let upstream = Upstream::start(testing::fixtures()).await;
+        let vars = [
+            ("UPSTREAM_API_KEY", "s3cret"),
+            ("UPSTREAM_ACCEPT", "application/vnd.fp+json"),
+        ];
+        let state = upstream.state(vars);
+
+        warm_up(&state, state.config().drivers_url.clone()).await;
+
+        let requests = upstream.requests_to("/_driverList.asp");
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, "HEAD");
+        assert_eq!(requests[0].headers["x-api-key"], "s3cret");
+        assert_eq!(requests[0].headers["accept"], "application/vnd.fp+json");
+    }
+
+    #[tokio::test]
+    async fn does_not_warm_up_in_mock_mode() {
+        let upstream = Upstream::start(testing::fixtures()).await;
+        let state = upstream.state([("MOCK_MODE", "true")]);
+
+        warm_up(&state, state.config().drivers_url.clone()).await;
+
+        assert!(upstream.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn curtails_upstream_calls_to_the_deadline() {
+        let slow = routing::get(|| async {
+            time::sleep(Duration::from_secs(5)).await;
+            "late"
+        });
+        let upstream = Upstream::start(Router::new().route("/slow", slow)).await;
+        let state = upstream.state([("REQUEST_TIMEOUT", "10")]);
+        let url = format!("{}slow", upstream.base());
+
+        let ctx = Context {
+            deadline: Some(Instant::now() + Duration::from_millis(200)),
+            ..Default::default()
+        };
+        let started = Instant::now();
+        assert!(send(&state, &ctx, state.client.get(&url)).await.is_err());
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        // Past the deadline, upstream is not called at all.
+        let ctx = Context {
+            deadline: Some(Instant::now()),
+            ..Default::default()
+        };
+        let Err(err) = send(&state, &ctx, state.client.get(&url)).await else {
+            panic!("the call should time out");
+        };
+        assert_eq!(err.into_response().status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(upstream.requests_to("/slow").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn answers_truncated_bodies_with_a_502() {
+        // Announces a longer body than it sends, then closes the
+        // connection.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}/", listener.local_addr().unwrap());
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                let _ = stream.read(&mut [0; 4096]);
+                let res = "HTTP/1.1 200 OK\r\ncontent-length: 100\r\n\r\n{\"Type\":";
+                let _ = stream.write_all(res.as_bytes());
+            }
+        });
+
+        let state = testing::state([
+            ("UPSTREAM_BASE", base.as_str()),
+            ("RETRY_ATTEMPTS", "1"),
+            ("RETRY_BASE_DELAY_MS", "1"),
+        ]);
+        let res = testing::send(&state, testing::get("/prices/EPSON-SCP9500")).await;
+
+        assert_eq!(res.status, StatusCode::BAD_GATEWAY);
+        assert_eq!(res.body["error"], "truncated upstream response");
+        // Retried as a transient failure.
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn budgets_each_host_separately() {
+        let permits = HostPermits::new(1);
+        let acquire = |host| time::timeout(Duration::from_millis(50), permits.acquire(host));
+
+        let held = acquire("a.example.com").await.unwrap().unwrap();
+        assert!(acquire("a.example.com").await.is_err());
+        let other = acquire("b.example.com").await;
+        assert!(other.is_ok(), "b.example.com should have its own budget");
+
+        drop(held);
+        assert!(acquire("a.example.com").await.is_ok());
+    }
+
+    #[test]
+    fn parses_retry_after() {
+        let retry_after = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(RETRY_AFTER, value.parse().unwrap());
+            parse_retry_after(&headers)
+        };
+
+        assert_eq!(retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(retry_after(" 5 "), Some(Duration::from_secs(5)));
+
+        let date = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(90));
+        let delay = retry_after(&date).unwrap();
+        assert!(delay > Duration::from_secs(85) && delay <= Duration::from_secs(90));
+        let past = httpdate::fmt_http_date(SystemTime::now() - Duration::from_secs(90));
+        assert_eq!(retry_after(&past), Some(Duration::ZERO));
+
+        assert_eq!(retry_after("soon"), None);
+        assert_eq!(parse_retry_after(&HeaderMap::new()), None);
+    }
+
+    #[tokio::test]
+    async fn backs_off_when_throttled() {
+        let throttling = routing::post(|| async {
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(RETRY_AFTER, "30")],
+                "slow down",
+            )
+        });
+        let upstream = Upstream::start(testing::drivers().route("/_prices.asp", throttling)).await;
+        let state = upstream.state([]);
+
+        let res = testing::send(&state, testing::get("/prices/EPSON-SCP9500")).await;
+        assert_eq!(res.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.header("retry-after"), Some("30"));
+        assert_eq!(res.body["error"], "upstream is throttling requests");
+
+        // Not even sent while throttled, the delay counting down.
+        let res = testing::send(&state, testing::get("/prices/HP-LATEX-800W")).await;
+        assert_eq!(res.status, StatusCode::SERVICE_UNAVAILABLE);
+        let retry_after: u64 = res.header("retry-after").unwrap().parse().unwrap();
+        assert!((29..=30).contains(&retry_after), "{retry_after}");
+        assert_eq!(upstream.requests_to("/_prices.asp").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn backs_off_by_default_without_retry_after() {
+        let throttling = routing::post(|| async { StatusCode::TOO_MANY_REQUESTS });
+        let upstream = Upstream::start(testing::drivers().route("/_prices.asp", throttling)).await;
+        let state = upstream.state([]);
+
+        let res = testing::send(&state, testing::get("/prices/EPSON-SCP9500")).await;
+        assert_eq!(res.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.header("retry-after"), Some("1"));
+    }
+}
@@ -0,0 +1,184 @@
+use anyhow::anyhow;
+use axum::http::StatusCode;
+
+use crate::{
+    config::Config,
+    drivers::DriversCache,
+    error::{Error, FieldError},
+};
+
+/// Bounds the upstream calls a single comparison fans out to.
+const MAX_COMPARED_CURRENCIES: usize = 10;
+
+/// Validates a basket before pricing it, reporting every problem at
+/// once. Product codes are only checked when drivers are given.
+pub fn validate_basket(
+    config: &Config,
+    drivers: Option<&DriversCache>,
+    products: &[(String, usize)],
+    currency: &str,
+) -> Result<(), Error> {
+    let mut errors = Vec::new();
+
+    if products.is_empty() {
+        errors.push(FieldError {
+            field: "products".into(),
+            message: "should contain at least one product".into(),
+        });
+    }
+
+    for (code, qty) in products {
+        if drivers.is_some_and(|drivers| !drivers.contains(code)) {
+            errors.push(FieldError {
+                field: format!("products.{code}"),
+                message: "should be a known product code".into(),
+            });
+        }
+
+        let field = format!("products.{code}.qty");
+
+        if *qty == 0 {
+            errors.push(FieldError {
+                field,
+                message: "should be greater than 0".into(),
+            });
+        } else if *qty > config.max_quantity {
+            errors.push(FieldError {
+                field,
+                message: format!("should not exceed {}", config.max_quantity),
+            });
+        }
+    }
+
+    if !is_currency(currency) {
+        errors.push(FieldError {
+            field: "currency".into(),
+            message: "should be an ISO 4217 currency code".into(),
+        });
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::invalid(errors))
+    }
+}
+
+/// Validates the currencies a basket is compared across.
+pub fn validate_currencies(currencies: &[String]) -> Result<(), Error> {
+    let mut errors = Vec::new();
+
+    if currencies.is_empty() {
+        errors.push(FieldError {
+            field: "currencies".into(),
+            message: "should contain at least one currency".into(),
+        });
+    } else if currencies.len() > MAX_COMPARED_CURRENCIES {
+        errors.push(FieldError {
+            field: "currencies".into(),
+            message: format!("should not contain more than {MAX_COMPARED_CURRENCIES} currencies"),
+        });
+    }
+
+    for (i, currency) in currencies.iter().enumerate() {
+        if !is_currency(currency) {
+            errors.push(FieldError {
+                field: format!("currencies.{i}"),
+                message: "should be an ISO 4217 currency code".into(),
+            });
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::invalid(errors))
+    }
+}
+
+fn is_currency(currency: &str) -> bool {
+    currency.len() == 3 && currency.bytes().all(|b| b.is_ascii_uppercase())
+}
+
+/// Rejects products missing from `ALLOWED_PRODUCTS`, listing them.
+pub fn check_allowed_products(config: &Config, products: &[(String, usize)]) -> Result<(), Error> {
+    let Some(allowed) = &config.allowed_products else {
+        return Ok(());
+    };
+
+    let mut disallowed: Vec<&str> = products
+        .iter()
+        .map(|(code, _)| code.as_str())
+        .filter(|code| !allowed.contains(*code))
+        .collect();
+
+    if disallowed.is_empty() {
+        return Ok(());
+    }
+
+    disallowed.sort_unstable();
+    let err = anyhow!("some products are not allowed");
+    Err(Error::new(StatusCode::FORBIDDEN, err).details(disallowed))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::{json, Value};
+
+    use crate::{drivers, testing};
+
+    #[tokio::test]
+    async fn reports_every_invalid_field() {
+        let state = testing::state([("MOCK_MODE", "true"), ("MAX_QUANTITY", "100")]);
+        drivers::refresh_drivers(&state).await.unwrap();
+
+        let basket = json!({ "UNKNOWN": 1, "EPSON-SCP9500": 0, "HP-LATEX-800W": 101 });
+        let req = testing::post("/prices/validate?currency=eur", basket);
+        let res = testing::send(&state, req).await;
+
+        assert_eq!(res.status, StatusCode::BAD_REQUEST);
+        let mut details = res.body["details"].as_array().unwrap().clone();
+        details.sort_by_key(|error| error["field"].to_string());
+
+        let error = |field: &str, message: &str| json!({ "field": field, "message": message });
+        assert_eq!(
+            details,
+            [
+                error("currency", "should be an ISO 4217 currency code"),
+                error("products.EPSON-SCP9500.qty", "should be greater than 0"),
+                error("products.HP-LATEX-800W.qty", "should not exceed 100"),
+                error("products.UNKNOWN", "should be a known product code"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_empty_baskets() {
+        let state = testing::state([("MOCK_MODE", "true")]);
+        let res = testing::send(&state, testing::post("/prices", json!({}))).await;
+
+        assert_eq!(res.status, StatusCode::BAD_REQUEST);
+        assert_eq!(res.body["details"][0]["field"], "products");
+        assert_eq!(res.body["details"][1], Value::Null);
+    }
+
+    #[tokio::test]
+    async fn restricts_products_to_the_allowlist() {
+        let allowed = "EPSON-SCP9500, HP-LATEX-800W";
+        let state = testing::state([("MOCK_MODE", "true"), ("ALLOWED_PRODUCTS", allowed)]);
+
+        let basket = json!({ "EPSON-SCP9500": 1, "HP-LATEX-800W": 2 });
+        let res = testing::send(&state, testing::post("/prices", basket)).await;
+        assert_eq!(res.status, StatusCode::OK);
+
+        let basket = json!({ "EPSON-SCP9500": 1, "ROLAND-VG3-640": 1, "MIMAKI-JV330": 1 });
+        let res = testing::send(&state, testing::post("/prices", basket)).await;
+        assert_eq!(res.status, StatusCode::FORBIDDEN);
+        assert_eq!(res.body["error"], "some products are not allowed");
+        assert_eq!(
+            res.body["details"],
+            json!(["MIMAKI-JV330", "ROLAND-VG3-640"])
+        );
+    }
+}
@@ -0,0 +1,139 @@
+use std::time::Duration;
+
+use reqwest::header::CONTENT_TYPE;
+use serde::Serialize;
+use tokio::time;
+use tracing::{debug, warn};
+
+use crate::{prices::Prices, state::AppState};
+
+/// Deliveries are given up after that many failed attempts.
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Delay before the first retry, doubled after each failure.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+#[derive(Serialize)]
+struct PriceChange {
+    old: Prices,
+    new: Prices,
+}
+
+/// Posts the old and new prices of a basket to `PRICE_CHANGE_WEBHOOK`,
+/// in the background so that deliveries never slow down nor fail
+/// client requests.
+pub fn notify_price_change(state: &AppState, old: Prices, new: Prices) {
+    let Some(url) = state
+        .config()
+        .price_change_webhook
+        .as_ref()
+        .map(|url| url.expose().to_owned())
+    else {
+        return;
+    };
+
+    let change = PriceChange { old, new };
+    let body = match serde_json::to_string(&change) {
+        Ok(body) => body,
+        Err(err) => {
+            warn!("cannot serialize price change: {err}");
+            return;
+        }
+    };
+
+    let client = state.client.clone();
+
+    tokio::spawn(async move {
+        let mut backoff = INITIAL_BACKOFF;
+
+        for attempt in 1..=MAX_ATTEMPTS {
+            let res = client
+                .post(&url)
+                .header(CONTENT_TYPE, "application/json")
+                .body(body.clone())
+                .send()
+                .await;
+
+            // The URL may carry credentials, it must not end up in logs.
+            let res = res
+                .and_then(|res| res.error_for_status())
+                .map_err(reqwest::Error::without_url);
+
+            match res {
+                Ok(_) => {
+                    debug!("price change delivered to webhook");
+                    return;
+                }
+                Err(err) if attempt < MAX_ATTEMPTS => {
+                    warn!("cannot deliver price change (attempt {attempt}), retrying: {err}");
+                    time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(err) => warn!("cannot deliver price change, giving up: {err}"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use axum::{routing, Json};
+    use serde_json::{json, Value};
+    use tokio::time;
+
+    use crate::{
+        prices,
+        testing::{self, Upstream},
+    };
+
+    #[tokio::test]
+    async fn posts_price_changes() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let prices = routing::post(move || async move {
+            let price = match calls.fetch_add(1, Ordering::SeqCst) {
+                0 => 29.9,
+                _ => 31.9,
+            };
+            Json(json!({ "Type": "Subscription", "Results": [["Connect", 30, 1, price]] }))
+        });
+        let routes = testing::drivers()
+            .route("/_prices.asp", prices)
+            .route("/hook", routing::post(|| async {}));
+        let upstream = Upstream::start(routes).await;
+        let webhook = format!("http://{}/hook", upstream.addr);
+        let state = upstream.state([("PRICE_CHANGE_WEBHOOK", webhook.as_str())]);
+
+        let products = vec![("EPSON-SCP9500".to_owned(), 1)];
+        for _ in 0..2 {
+            prices::prefetch_prices(&state, products.clone(), "EUR")
+                .await
+                .unwrap();
+        }
+
+        let mut deliveries = Vec::new();
+        for _ in 0..50 {
+            deliveries = upstream.requests_to("/hook");
+            if !deliveries.is_empty() {
+                break;
+            }
+            time::sleep(Duration::from_millis(20)).await;
+        }
+
+        assert_eq!(deliveries.len(), 1);
+        let content_type = &deliveries[0].headers["content-type"];
+        assert_eq!(content_type, "application/json");
+
+        let change: Value = serde_json::from_slice(&deliveries[0].body).unwrap();
+        assert_eq!(change["old"]["monthly"]["connect"], 2990);
+        assert_eq!(change["new"]["monthly"]["connect"], 3190);
+        assert_eq!(change["old"]["basket"], change["new"]["basket"]);
+    }
+}